@@ -0,0 +1,313 @@
+use config::AwsAutoScalingPollerSettingsProvider;
+use std::str::FromStr;
+use std::io::{stderr, Write};
+use std::collections::HashMap;
+use rusoto::Region;
+use rusoto::autoscaling;
+use prometheus::{Opts, GaugeVec, Collector};
+use pagination::{PaginatedIterator, PaginatedRequestor};
+use poller::{Poller, CollectorGroup};
+use aws_poller::{AwsPollerError, PollerResult, current_series, set_series,
+                 remove_stale_series};
+use credentials::CredentialsProviderWrapper;
+use aws_connector::{HttpClientFactory, SharedHttpClient};
+
+impl_from_aws_error!(autoscaling, DescribeAutoScalingGroupsError, "DescribeAutoScalingGroups");
+
+type AutoscalingClient = autoscaling::AutoscalingClient<CredentialsProviderWrapper, SharedHttpClient>;
+
+/// Lifecycle states which are always exported, so that missing instances are reported as zero.
+const REPORTED_LIFECYCLE_STATES: &'static [&'static str] = &["InService", "Pending", "Terminating"];
+
+pub struct AwsAutoScalingPoller {
+    credentials_provider: CredentialsProviderWrapper,
+    region: Region,
+    http_client: SharedHttpClient,
+    max_chunk_size: Option<i64>,
+    min_sizes: GaugeVec,
+    max_sizes: GaugeVec,
+    desired_capacities: GaugeVec,
+    instance_counts: GaugeVec,
+    instances: GaugeVec,
+    suspended_processes: GaugeVec
+}
+
+impl AwsAutoScalingPoller {
+    /// Returns `None` if the poller is not configured.
+    pub fn new(settings_provider: &AwsAutoScalingPollerSettingsProvider,
+               http_clients: &HttpClientFactory) -> PollerResult<Option<Self>> {
+        let settings = match settings_provider.aws_autoscaling_poller_settings() {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        let region = Region::from_str(&settings.region)?;
+        let credentials_provider = CredentialsProviderWrapper::from_type(
+            "autoscaling", settings.credentials_provider, settings.profile_name, settings.credentials_file,
+            region, http_clients)?;
+        let http_client = http_clients.client(settings.endpoint_url.as_ref().map(|s| &**s),
+                                              &credentials_provider, region)?;
+        let result = AwsAutoScalingPoller {
+            credentials_provider: credentials_provider,
+            region: region,
+            http_client: http_client,
+            max_chunk_size: settings.max_chunk_size,
+            min_sizes: GaugeVec::new(
+                Opts::new("aws_autoscaling_group_min_size", "Minimum size of AWS auto scaling group"),
+                &["name"])?,
+            max_sizes: GaugeVec::new(
+                Opts::new("aws_autoscaling_group_max_size", "Maximum size of AWS auto scaling group"),
+                &["name"])?,
+            desired_capacities: GaugeVec::new(
+                Opts::new("aws_autoscaling_group_desired_capacity", "Desired capacity of AWS auto scaling group"),
+                &["name"])?,
+            instance_counts: GaugeVec::new(
+                Opts::new("aws_autoscaling_group_instances",
+                          "Number of AWS auto scaling group instances by lifecycle state"),
+                &["name", "lifecycle_state"])?,
+            instances: GaugeVec::new(
+                Opts::new("aws_autoscaling_group_instance", "Identifies an instance of AWS auto scaling group"),
+                &["name", "id", "lifecycle_state", "health_status"])?,
+            suspended_processes: GaugeVec::new(
+                Opts::new("aws_autoscaling_group_suspended_process",
+                          "Identifies a suspended process of AWS auto scaling group"),
+                &["name", "process"])?,
+        };
+        Ok(Some(result))
+    }
+
+    fn get_autoscaling_client(&self) -> AutoscalingClient {
+        AutoscalingClient::new(self.http_client.clone(), self.credentials_provider.clone(), self.region)
+    }
+
+    /// Auto Scaling API does not support dry run, so a single group is requested instead.
+    fn test_describe_auto_scaling_groups(&self) -> Option<AwsPollerError> {
+        let client = self.get_autoscaling_client();
+        let mut req: autoscaling::AutoScalingGroupNamesType = Default::default();
+        req.max_records = Some(1);
+        client.describe_auto_scaling_groups(&req).err().map(AwsPollerError::from)
+    }
+}
+
+impl Poller for AwsAutoScalingPoller {
+    fn name(&self) -> &str {
+        "autoscaling"
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        self.credentials_provider.test().or_else(|| self.test_describe_auto_scaling_groups())
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let mut stale_min_sizes = current_series(&self.min_sizes);
+        let mut stale_max_sizes = current_series(&self.max_sizes);
+        let mut stale_desired = current_series(&self.desired_capacities);
+        let mut stale_counts = current_series(&self.instance_counts);
+        let mut stale_instances = current_series(&self.instances);
+        let mut stale_processes = current_series(&self.suspended_processes);
+        let mut query_err = None;
+        {
+            let groups = PaginatedIterator::new(
+                DescribeAutoScalingGroupsRequestor::new(self.get_autoscaling_client(), self.max_chunk_size),
+                &mut query_err);
+            for g in groups {
+                let series = GroupSeries::new(g);
+                set_series(&self.min_sizes, &mut stale_min_sizes, &series.name, series.min_size);
+                set_series(&self.max_sizes, &mut stale_max_sizes, &series.name, series.max_size);
+                set_series(&self.desired_capacities, &mut stale_desired, &series.name, series.desired_capacity);
+                for (labels, count) in series.instance_counts {
+                    set_series(&self.instance_counts, &mut stale_counts, &labels, count);
+                }
+                for labels in series.instances {
+                    set_series(&self.instances, &mut stale_instances, &labels, 1.0);
+                }
+                for labels in series.suspended_processes {
+                    set_series(&self.suspended_processes, &mut stale_processes, &labels, 1.0);
+                }
+            }
+        }
+        if let Some(e) = query_err.map(AwsPollerError::from) {
+            let _ = writeln!(&mut stderr(), "Unexpected error during auto scaling groups enumeration: {:?}", e);
+            self.credentials_provider.revalidate_on(&e);
+            return Err(e);
+        }
+        remove_stale_series(&self.min_sizes, &stale_min_sizes);
+        remove_stale_series(&self.max_sizes, &stale_max_sizes);
+        remove_stale_series(&self.desired_capacities, &stale_desired);
+        remove_stale_series(&self.instance_counts, &stale_counts);
+        remove_stale_series(&self.instances, &stale_instances);
+        remove_stale_series(&self.suspended_processes, &stale_processes);
+        Ok(())
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(vec![
+            Box::new(self.min_sizes.clone()),
+            Box::new(self.max_sizes.clone()),
+            Box::new(self.desired_capacities.clone()),
+            Box::new(self.instance_counts.clone()),
+            Box::new(self.instances.clone()),
+            Box::new(self.suspended_processes.clone()),
+            self.credentials_provider.counters(),
+        ]))
+    }
+}
+
+/// Series of a single auto scaling group, all of them are labelled with the group name.
+struct GroupSeries {
+    name: Vec<(String, String)>,
+    min_size: f64,
+    max_size: f64,
+    desired_capacity: f64,
+    /// Sorted by lifecycle state.
+    instance_counts: Vec<(Vec<(String, String)>, f64)>,
+    instances: Vec<Vec<(String, String)>>,
+    suspended_processes: Vec<Vec<(String, String)>>
+}
+
+impl GroupSeries {
+    fn new(g: autoscaling::AutoScalingGroup) -> GroupSeries {
+        let name = vec![("name".to_owned(), g.auto_scaling_group_name.clone())];
+        let with_name = |label: &str, value: String| {
+            let mut labels = name.clone();
+            labels.push((label.to_owned(), value));
+            labels
+        };
+        let mut counts: HashMap<String, u32> = REPORTED_LIFECYCLE_STATES.iter()
+            .map(|s| (s.to_string(), 0)).collect();
+        let mut instances = Vec::new();
+        for i in g.instances.unwrap_or(Vec::new()) {
+            // Pending:Wait, Terminating:Proceed and alike are accounted with their main state
+            let state = i.lifecycle_state.split(':').next().unwrap_or("").to_owned();
+            *counts.entry(state).or_insert(0) += 1;
+            instances.push(vec![
+                ("name".to_owned(), g.auto_scaling_group_name.clone()),
+                ("id".to_owned(), i.instance_id),
+                ("lifecycle_state".to_owned(), i.lifecycle_state),
+                ("health_status".to_owned(), i.health_status)
+            ]);
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort();
+        GroupSeries {
+            min_size: g.min_size as f64,
+            max_size: g.max_size as f64,
+            desired_capacity: g.desired_capacity as f64,
+            instance_counts: counts.into_iter()
+                .map(|(state, count)| (with_name("lifecycle_state", state), count as f64))
+                .collect(),
+            instances: instances,
+            suspended_processes: g.suspended_processes.unwrap_or(Vec::new()).into_iter()
+                .map(|p| with_name("process", p.process_name.unwrap_or(String::new())))
+                .collect(),
+            name: name.clone()
+        }
+    }
+}
+
+struct DescribeAutoScalingGroupsRequestor {
+    client: AutoscalingClient,
+    req: autoscaling::AutoScalingGroupNamesType,
+    first_chunk: bool
+}
+
+impl PaginatedRequestor for DescribeAutoScalingGroupsRequestor {
+    type Item = autoscaling::AutoScalingGroup;
+    type Error = autoscaling::DescribeAutoScalingGroupsError;
+    fn next_page(&mut self) -> Result<Option<Vec<Self::Item>>, Self::Error> {
+        if self.req.next_token.is_none() && !self.first_chunk {
+            return Ok(None);
+        }
+        self.first_chunk = false;
+        match self.client.describe_auto_scaling_groups(&self.req) {
+            Ok(resp) => {
+                self.req.next_token = resp.next_token.clone();
+                Ok(Some(resp.auto_scaling_groups))
+            }
+            Err(e) => {
+                Err(e)
+            }
+        }
+    }
+}
+
+impl DescribeAutoScalingGroupsRequestor {
+    fn new(client: AutoscalingClient, chunk_size: Option<i64>) -> Self {
+        let mut req: autoscaling::AutoScalingGroupNamesType = Default::default();
+        req.max_records = chunk_size;
+        DescribeAutoScalingGroupsRequestor {
+            client: client,
+            req: req,
+            first_chunk: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(n, v)| (n.to_owned(), v.to_owned())).collect()
+    }
+
+    fn instance(id: &str, lifecycle_state: &str) -> autoscaling::Instance {
+        autoscaling::Instance {
+            instance_id: id.to_owned(),
+            lifecycle_state: lifecycle_state.to_owned(),
+            health_status: "Healthy".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exports_group_capacities() {
+        let series = GroupSeries::new(autoscaling::AutoScalingGroup {
+            auto_scaling_group_name: "web".to_owned(),
+            min_size: 1,
+            max_size: 10,
+            desired_capacity: 3,
+            ..Default::default()
+        });
+        assert_eq!(series.name, labels(&[("name", "web")]));
+        assert_eq!((series.min_size, series.max_size, series.desired_capacity), (1.0, 10.0, 3.0));
+        assert!(series.instances.is_empty());
+        assert!(series.suspended_processes.is_empty());
+    }
+
+    #[test]
+    fn counts_instances_by_main_lifecycle_state() {
+        let series = GroupSeries::new(autoscaling::AutoScalingGroup {
+            auto_scaling_group_name: "web".to_owned(),
+            instances: Some(vec![
+                instance("i-1", "InService"),
+                instance("i-2", "InService"),
+                instance("i-3", "Pending:Wait"),
+                instance("i-4", "Standby")
+            ]),
+            ..Default::default()
+        });
+        // reported states are exported even without instances
+        assert_eq!(series.instance_counts, vec![
+            (labels(&[("name", "web"), ("lifecycle_state", "InService")]), 2.0),
+            (labels(&[("name", "web"), ("lifecycle_state", "Pending")]), 1.0),
+            (labels(&[("name", "web"), ("lifecycle_state", "Standby")]), 1.0),
+            (labels(&[("name", "web"), ("lifecycle_state", "Terminating")]), 0.0)
+        ]);
+        assert_eq!(series.instances[2], labels(&[("name", "web"), ("id", "i-3"),
+                                                 ("lifecycle_state", "Pending:Wait"),
+                                                 ("health_status", "Healthy")]));
+    }
+
+    #[test]
+    fn exports_suspended_processes() {
+        let series = GroupSeries::new(autoscaling::AutoScalingGroup {
+            auto_scaling_group_name: "web".to_owned(),
+            suspended_processes: Some(vec![autoscaling::SuspendedProcess {
+                process_name: Some("Launch".to_owned()),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        });
+        assert_eq!(series.suspended_processes, vec![labels(&[("name", "web"), ("process", "Launch")])]);
+    }
+}
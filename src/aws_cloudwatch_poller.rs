@@ -0,0 +1,519 @@
+use config::{AwsCloudWatchPollerSettingsProvider, CloudWatchMetricSettings,
+             AwsInstancesPollerSettingsProvider};
+use std::str::FromStr;
+use std::io::{stderr, Write};
+use std::collections::HashMap;
+use rusoto::Region;
+use rusoto::cloudwatch;
+use prometheus::{Opts, GaugeVec, Collector};
+use poller::{Poller, CollectorGroup};
+use rusoto::SignedRequest;
+use url::form_urlencoded;
+use aws_poller::{AwsPollerError, PollerResult, AwsAccount, InstanceInventory, InventoryInstance,
+                 DEFAULT_MAX_PARALLEL_REQUESTS, instance_regions, instance_accounts, owner_label_names,
+                 instance_label_names, current_series, set_series, remove_stale_series};
+use credentials::CredentialsProviderWrapper;
+use aws_connector::{HttpClientFactory, SharedHttpClient};
+use aws_request::{self, xml_element_text, xml_list_members};
+use fan_out::fan_out;
+
+impl_from_aws_error!(cloudwatch, GetMetricStatisticsError, "GetMetricStatistics");
+
+pub type CloudWatchClient = cloudwatch::CloudWatchClient<CredentialsProviderWrapper, SharedHttpClient>;
+
+/// Converts CloudWatch names like `AWS/EC2` or `CPUUtilization` to `aws_ec2` and `cpu_utilization`.
+pub fn to_snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut result = String::with_capacity(name.len() + 4);
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev_lower = chars[i - 1].is_lowercase() || chars[i - 1].is_numeric();
+            let next_lower = chars.get(i + 1).map(|n| n.is_lowercase()).unwrap_or(false);
+            if (prev_lower || (next_lower && chars[i - 1].is_uppercase())) && !result.ends_with('_') {
+                result.push('_');
+            }
+        }
+        if c.is_ascii() && c.is_alphanumeric() {
+            result.extend(c.to_lowercase());
+        } else if !result.ends_with('_') {
+            result.push('_');
+        }
+    }
+    result
+}
+
+/// Returns the value of the requested statistic of the datapoint.
+pub fn datapoint_value(datapoint: &cloudwatch::Datapoint, statistic: &str) -> Option<f64> {
+    match statistic {
+        "Average" => datapoint.average,
+        "Sum" => datapoint.sum,
+        "Minimum" => datapoint.minimum,
+        "Maximum" => datapoint.maximum,
+        "SampleCount" => datapoint.sample_count,
+        _ => None
+    }
+}
+
+/// Requests statistics for the window of `period` seconds ending `lag` seconds ago and returns the
+/// latest datapoint.
+pub fn get_latest_datapoint(client: &CloudWatchClient, namespace: &str, metric_name: &str,
+                            dimensions: Vec<cloudwatch::Dimension>, statistic: &str,
+                            period: i64, lag: i64) -> PollerResult<Option<cloudwatch::Datapoint>> {
+    let end_time = ::time::now_utc() - ::time::Duration::seconds(lag);
+    let start_time = end_time - ::time::Duration::seconds(period);
+    let req = cloudwatch::GetMetricStatisticsInput {
+        namespace: namespace.to_owned(),
+        metric_name: metric_name.to_owned(),
+        dimensions: if dimensions.is_empty() { None } else { Some(dimensions) },
+        statistics: Some(vec![statistic.to_owned()]),
+        period: period,
+        start_time: format!("{}", start_time.strftime("%FT%T").unwrap()),
+        end_time: format!("{}", end_time.strftime("%FT%T").unwrap()),
+        ..Default::default()
+    };
+    let datapoints = client.get_metric_statistics(&req)?.datapoints.unwrap_or(Vec::new());
+    Ok(datapoints.into_iter().max_by_key(|d| d.timestamp.clone()))
+}
+
+struct CloudWatchMetric {
+    settings: CloudWatchMetricSettings,
+    gauges: GaugeVec
+}
+
+pub struct AwsCloudWatchPoller {
+    credentials_provider: CredentialsProviderWrapper,
+    region: Region,
+    http_client: SharedHttpClient,
+    lag: i64,
+    metrics: Vec<CloudWatchMetric>,
+    gauges: Vec<GaugeVec>
+}
+
+impl AwsCloudWatchPoller {
+    /// Returns `None` if the poller is not configured.
+    pub fn new(settings_provider: &AwsCloudWatchPollerSettingsProvider,
+               http_clients: &HttpClientFactory) -> PollerResult<Option<Self>> {
+        let settings = match settings_provider.aws_cloudwatch_poller_settings() {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        // metrics sharing the same name are exposed by the same gauge
+        let mut gauges_by_name: HashMap<String, (Vec<String>, GaugeVec)> = HashMap::new();
+        let mut metrics = Vec::with_capacity(settings.metrics.len());
+        for m in settings.metrics {
+            let name = Self::gauge_name(&m);
+            let mut labels: Vec<String> = m.dimensions.as_ref()
+                .map(|d| d.keys().map(|k| to_snake_case(k)).collect())
+                .unwrap_or(Vec::new());
+            labels.sort();
+            if !gauges_by_name.contains_key(&name) {
+                let help = format!("CloudWatch {} {} of {}", m.namespace, m.statistic, m.metric_name);
+                let label_names: Vec<&str> = labels.iter().map(|l| &**l).collect();
+                let gauges = GaugeVec::new(Opts::new(&name, &help), label_names.as_slice())?;
+                gauges_by_name.insert(name.clone(), (labels.clone(), gauges));
+            }
+            let &(ref known_labels, ref gauges) = &gauges_by_name[&name];
+            if *known_labels != labels {
+                return Err(AwsPollerError::InvalidConfiguration(
+                    format!("Metric {} is configured with different sets of dimensions", name)));
+            }
+            metrics.push(CloudWatchMetric {
+                settings: m,
+                gauges: gauges.clone()
+            });
+        }
+        let region = Region::from_str(&settings.region)?;
+        let credentials_provider = CredentialsProviderWrapper::from_type(
+            "cloudwatch", settings.credentials_provider, settings.profile_name, settings.credentials_file,
+            region, http_clients)?;
+        let http_client = http_clients.client(settings.endpoint_url.as_ref().map(|s| &**s),
+                                              &credentials_provider, region)?;
+        let result = AwsCloudWatchPoller {
+            credentials_provider: credentials_provider,
+            region: region,
+            http_client: http_client,
+            lag: settings.lag.unwrap_or(600),
+            metrics: metrics,
+            gauges: gauges_by_name.into_iter().map(|(_, (_, g))| g).collect()
+        };
+        Ok(Some(result))
+    }
+
+    fn gauge_name(settings: &CloudWatchMetricSettings) -> String {
+        settings.name.clone().unwrap_or(format!("{}_{}_{}", to_snake_case(&settings.namespace),
+                                                to_snake_case(&settings.metric_name),
+                                                to_snake_case(&settings.statistic)))
+    }
+
+    fn get_cloudwatch_client(&self) -> CloudWatchClient {
+        CloudWatchClient::new(self.http_client.clone(), self.credentials_provider.clone(), self.region)
+    }
+
+    fn poll_metric(&self, client: &CloudWatchClient, metric: &CloudWatchMetric) -> PollerResult<()> {
+        let s = &metric.settings;
+        let dimensions: Vec<(String, String)> = s.dimensions.as_ref()
+            .map(|d| d.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or(Vec::new());
+        let datapoint = get_latest_datapoint(
+            client, &s.namespace, &s.metric_name,
+            dimensions.iter().map(|&(ref k, ref v)| cloudwatch::Dimension { name: k.clone(), value: v.clone() })
+                .collect(),
+            &s.statistic, s.period.unwrap_or(300), self.lag)?;
+        let labels: HashMap<String, String> = dimensions.into_iter()
+            .map(|(k, v)| (to_snake_case(&k), v)).collect();
+        let labels = labels.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect::<HashMap<_, _>>();
+        match datapoint.and_then(|d| datapoint_value(&d, &s.statistic)) {
+            Some(v) => metric.gauges.get_metric_with(&labels)?.set(v),
+            // no datapoints in the window, do not report the stale value
+            None => { let _ = metric.gauges.remove(&labels); }
+        }
+        Ok(())
+    }
+}
+
+impl Poller for AwsCloudWatchPoller {
+    fn name(&self) -> &str {
+        "cloudwatch"
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        self.credentials_provider.test()
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let client = self.get_cloudwatch_client();
+        let mut result = Ok(());
+        for metric in self.metrics.iter() {
+            if let Err(e) = self.poll_metric(&client, metric) {
+                let _ = writeln!(&mut stderr(), "Unexpected error during {}/{} statistics query: {:?}",
+                                 metric.settings.namespace, metric.settings.metric_name, e);
+                result = Err(e);
+            }
+        }
+        // credentials are re-validated once rather than for every failed query
+        if let Err(ref e) = result {
+            self.credentials_provider.revalidate_on(e);
+        }
+        result
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(self.gauges.iter()
+            .map(|g| Box::new(g.clone()) as Box<Collector>)
+            .chain(Some(self.credentials_provider.counters()))
+            .collect()))
+    }
+}
+
+const DEFAULT_INSTANCE_METRICS: &'static [&'static str] = &["CPUUtilization", "NetworkIn", "NetworkOut",
+                                                           "CPUCreditBalance"];
+
+/// Maximum number of queries of a single `GetMetricData` call.
+const MAX_METRIC_DATA_QUERIES: usize = 500;
+
+/// Latest value of a metric statistic requested with `GetMetricData`.
+struct MetricQuery {
+    namespace: String,
+    metric_name: String,
+    dimensions: Vec<(String, String)>,
+    statistic: String
+}
+
+/// Requests the latest values of the metrics in the window of `period` seconds ending `lag` seconds
+/// ago with a single `GetMetricData` call, following pages of the response. Values are returned in
+/// the order of queries, `None` if a metric has no datapoints in the window.
+fn get_metric_data(http_client: &SharedHttpClient, credentials_provider: &CredentialsProviderWrapper,
+                   region: Region, queries: &Vec<MetricQuery>, period: i64, lag: i64)
+                   -> PollerResult<Vec<Option<f64>>> {
+    let end_time = ::time::now_utc() - ::time::Duration::seconds(lag);
+    let start_time = end_time - ::time::Duration::seconds(period);
+    let mut values: Vec<Option<f64>> = queries.iter().map(|_| None).collect();
+    let mut next_token: Option<String> = None;
+    loop {
+        let mut body = form_urlencoded::Serializer::new(String::new());
+        body.append_pair("Action", "GetMetricData")
+            .append_pair("Version", "2010-08-01")
+            .append_pair("StartTime", &format!("{}", start_time.strftime("%FT%TZ").unwrap()))
+            .append_pair("EndTime", &format!("{}", end_time.strftime("%FT%TZ").unwrap()))
+            .append_pair("ScanBy", "TimestampDescending");
+        for (i, q) in queries.iter().enumerate() {
+            let query = format!("MetricDataQueries.member.{}", i + 1);
+            body.append_pair(&format!("{}.Id", query), &format!("m{}", i))
+                .append_pair(&format!("{}.MetricStat.Metric.Namespace", query), &q.namespace)
+                .append_pair(&format!("{}.MetricStat.Metric.MetricName", query), &q.metric_name)
+                .append_pair(&format!("{}.MetricStat.Period", query), &period.to_string())
+                .append_pair(&format!("{}.MetricStat.Stat", query), &q.statistic)
+                .append_pair(&format!("{}.ReturnData", query), "true");
+            for (j, &(ref name, ref value)) in q.dimensions.iter().enumerate() {
+                let dimension = format!("{}.MetricStat.Metric.Dimensions.member.{}", query, j + 1);
+                body.append_pair(&format!("{}.Name", dimension), name)
+                    .append_pair(&format!("{}.Value", dimension), value);
+            }
+        }
+        if let Some(ref token) = next_token {
+            body.append_pair("NextToken", token);
+        }
+        let mut request = SignedRequest::new("POST", "monitoring", region, "/");
+        request.set_content_type("application/x-www-form-urlencoded".to_owned());
+        request.set_payload(Some(body.finish().into_bytes()));
+        let document = match aws_request::send_signed(http_client, credentials_provider, request)? {
+            Ok(d) => d,
+            Err(e) => return Err(e.into_poller_error("GetMetricData"))
+        };
+        let (results, token) = parse_metric_data(&document)?;
+        for (id, value) in results {
+            // datapoints are scanned from the latest, so the first one seen is the latest one
+            let index = if id.starts_with('m') { id[1..].parse::<usize>().ok() } else { None };
+            if let Some(slot) = index.and_then(|i| values.get_mut(i)) {
+                if slot.is_none() {
+                    *slot = value;
+                }
+            }
+        }
+        next_token = match token {
+            Some(t) => Some(t),
+            None => return Ok(values)
+        };
+    }
+}
+
+/// Returns the id and the first value of every metric in the `GetMetricData` response, and the
+/// token of the next page.
+fn parse_metric_data(document: &str) -> PollerResult<(Vec<(String, Option<f64>)>, Option<String>)> {
+    let mut results = Vec::new();
+    for item in xml_list_members(document, "MetricDataResults") {
+        let id = xml_element_text(item, "Id").unwrap_or("").to_owned();
+        match xml_element_text(item, "StatusCode") {
+            Some("Complete") | Some("PartialData") | None => {}
+            Some(status) => return Err(AwsPollerError::UnknownError(
+                format!("GetMetricData query {} failed with {}", id, status)))
+        }
+        let value = xml_list_members(item, "Values").first().and_then(|v| v.trim().parse().ok());
+        results.push((id, value));
+    }
+    let next_token = match xml_element_text(document, "NextToken") {
+        Some(t) if !t.is_empty() => Some(t.to_owned()),
+        _ => None
+    };
+    Ok((results, next_token))
+}
+
+fn instance_id(instance: &InventoryInstance) -> Option<&str> {
+    instance.labels.iter().find(|l| l.0 == "id").map(|l| l.1.as_str())
+}
+
+/// Exports CloudWatch metrics of instances discovered by `AwsInstancesPoller` with the same labels.
+/// Every instance is queried in the account and the region it was discovered in, metrics of many
+/// instances are requested in a single `GetMetricData` call.
+pub struct AwsInstanceCloudWatchPoller {
+    accounts: Vec<AwsAccount>,
+    statistic: String,
+    period: i64,
+    lag: i64,
+    batch_size: usize,
+    max_parallel_requests: usize,
+    inventory: InstanceInventory,
+    metrics: Vec<(String, GaugeVec)>
+}
+
+impl AwsInstanceCloudWatchPoller {
+    /// Returns `None` if CloudWatch metrics are not enabled for the instances poller.
+    pub fn new(settings_provider: &AwsInstancesPollerSettingsProvider,
+               http_clients: &HttpClientFactory,
+               inventory: InstanceInventory) -> PollerResult<Option<Self>> {
+        let settings = settings_provider.aws_instances_poller_settings();
+        let cloudwatch_settings = match settings.cloudwatch {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        let metric_names = cloudwatch_settings.metrics
+            .unwrap_or(DEFAULT_INSTANCE_METRICS.iter().map(|s| s.to_string()).collect());
+        let statistic = cloudwatch_settings.statistic.unwrap_or("Average".to_owned());
+        let label_names = instance_label_names(&owner_label_names(&settings), &settings.expose_tags);
+        let labels: Vec<&str> = label_names.iter().map(|s| &**s).collect();
+        let mut metrics = Vec::with_capacity(metric_names.len());
+        for m in metric_names {
+            let opts = Opts::new(&format!("aws_instance_{}", to_snake_case(&m)),
+                                 &format!("CloudWatch {} of AWS/EC2 {} of a running AWS instance", statistic, m));
+            let gauges = GaugeVec::new(opts, labels.as_slice())?;
+            metrics.push((m, gauges));
+        }
+        let batch_size = cloudwatch_settings.batch_size.unwrap_or(MAX_METRIC_DATA_QUERIES);
+        if batch_size == 0 || batch_size > MAX_METRIC_DATA_QUERIES {
+            return Err(AwsPollerError::InvalidConfiguration(
+                format!("CloudWatch batch_size must be between 1 and {}", MAX_METRIC_DATA_QUERIES)));
+        }
+        let regions = instance_regions(&settings)?;
+        let result = AwsInstanceCloudWatchPoller {
+            accounts: instance_accounts("instance_cloudwatch", &settings, &regions, http_clients)?,
+            statistic: statistic,
+            period: cloudwatch_settings.period.unwrap_or(300),
+            lag: cloudwatch_settings.lag.unwrap_or(600),
+            batch_size: batch_size,
+            max_parallel_requests: settings.max_parallel_requests.unwrap_or(DEFAULT_MAX_PARALLEL_REQUESTS),
+            inventory: inventory,
+            metrics: metrics
+        };
+        Ok(Some(result))
+    }
+
+    /// Splits queries of every metric of every instance into batches of a single account and
+    /// region. Returns the account index, region and `(instance, metric)` indices of every batch.
+    fn batches(&self, instances: &Vec<InventoryInstance>) -> Vec<(usize, Region, Vec<(usize, usize)>)> {
+        let mut groups: Vec<(usize, Region, Vec<(usize, usize)>)> = Vec::new();
+        for (i, instance) in instances.iter().enumerate() {
+            // the inventory may still list instances of an account removed by a reload
+            let account = match self.accounts.iter().position(|a| a.name == instance.account) {
+                Some(a) => a,
+                None => continue
+            };
+            if instance_id(instance).is_none() {
+                continue;
+            }
+            let position = groups.iter().position(|g| g.0 == account && g.1 == instance.region);
+            let group = match position {
+                Some(g) => g,
+                None => {
+                    groups.push((account, instance.region, Vec::new()));
+                    groups.len() - 1
+                }
+            };
+            groups[group].2.extend((0..self.metrics.len()).map(|m| (i, m)));
+        }
+        groups.into_iter()
+            .flat_map(|(account, region, series)| series.chunks(self.batch_size)
+                .map(|c| (account, region, c.to_vec()))
+                .collect::<Vec<_>>())
+            .collect()
+    }
+}
+
+impl Poller for AwsInstanceCloudWatchPoller {
+    fn name(&self) -> &str {
+        "instance_cloudwatch"
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        self.accounts.iter()
+            .filter_map(|a| a.credentials_provider.test())
+            .next()
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let instances = self.inventory.instances();
+        let batches = self.batches(&instances);
+        let requests: Vec<_> = batches.iter()
+            .map(|&(account, region, ref series)| {
+                let queries: Vec<MetricQuery> = series.iter()
+                    .map(|&(i, m)| MetricQuery {
+                        namespace: "AWS/EC2".to_owned(),
+                        metric_name: self.metrics[m].0.clone(),
+                        dimensions: vec![("InstanceId".to_owned(),
+                                          instance_id(&instances[i]).unwrap_or("").to_owned())],
+                        statistic: self.statistic.clone()
+                    })
+                    .collect();
+                (self.accounts[account].clone(), region, queries)
+            })
+            .collect();
+        let (period, lag) = (self.period, self.lag);
+        let results = fan_out(requests, self.max_parallel_requests, move |(account, region, queries)| {
+            get_metric_data(&account.http_client(region), &account.credentials_provider, region, &queries,
+                            period, lag)
+        });
+        let mut stale: Vec<_> = self.metrics.iter().map(|&(_, ref g)| current_series(g)).collect();
+        let mut failed = false;
+        let mut last_errs = HashMap::new();
+        for ((account, _, series), result) in batches.into_iter().zip(results) {
+            match result {
+                Some(Ok(values)) => for ((i, m), value) in series.into_iter().zip(values) {
+                    if let Some(v) = value {
+                        set_series(&self.metrics[m].1, &mut stale[m], &instances[i].labels, v);
+                    }
+                },
+                Some(Err(e)) => {
+                    failed = true;
+                    let _ = writeln!(&mut stderr(), "Unexpected error during CloudWatch query of {} account: {:?}",
+                                     self.accounts[account].name, e);
+                    last_errs.insert(account, e);
+                }
+                None => failed = true
+            }
+        }
+        // credentials are re-validated once per account rather than for every failed query
+        for (&account, e) in last_errs.iter() {
+            self.accounts[account].credentials_provider.revalidate_on(e);
+        }
+        if failed {
+            return Err(last_errs.into_iter().map(|(_, e)| e).next().unwrap_or(
+                AwsPollerError::UnknownError("Instance query panicked".to_owned())));
+        }
+        for (i, &(_, ref gauges)) in self.metrics.iter().enumerate() {
+            remove_stale_series(gauges, &stale[i]);
+        }
+        Ok(())
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(self.metrics.iter()
+            .map(|&(_, ref g)| Box::new(g.clone()) as Box<Collector>)
+            .chain(self.accounts.iter().map(|a| a.credentials_provider.counters()))
+            .collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_metric_names_to_snake_case() {
+        assert_eq!(to_snake_case("CPUUtilization"), "cpu_utilization");
+        assert_eq!(to_snake_case("NetworkIn"), "network_in");
+        assert_eq!(to_snake_case("StatusCheckFailed_System"), "status_check_failed_system");
+        assert_eq!(to_snake_case("EBSIOBalance%"), "ebsio_balance_");
+    }
+
+    #[test]
+    fn selects_datapoint_statistic() {
+        let datapoint = cloudwatch::Datapoint {
+            average: Some(1.5),
+            maximum: Some(3.0),
+            ..Default::default()
+        };
+        assert_eq!(datapoint_value(&datapoint, "Average"), Some(1.5));
+        assert_eq!(datapoint_value(&datapoint, "Maximum"), Some(3.0));
+        assert_eq!(datapoint_value(&datapoint, "Sum"), None);
+        assert_eq!(datapoint_value(&datapoint, "p99"), None);
+    }
+
+    #[test]
+    fn parses_metric_data_results() {
+        let document = "<GetMetricDataResponse><GetMetricDataResult><MetricDataResults>\
+            <member><Id>m0</Id><Label>CPUUtilization</Label><StatusCode>Complete</StatusCode>\
+            <Timestamps><member>2017-05-01T12:05:00Z</member><member>2017-05-01T12:00:00Z</member></Timestamps>\
+            <Values><member>12.5</member><member>10</member></Values></member>\
+            <member><Id>m1</Id><StatusCode>PartialData</StatusCode><Timestamps/><Values/></member>\
+            </MetricDataResults><NextToken>abc</NextToken></GetMetricDataResult></GetMetricDataResponse>";
+        let (results, next_token) = parse_metric_data(document).unwrap();
+        assert_eq!(results, vec![("m0".to_owned(), Some(12.5)), ("m1".to_owned(), None)]);
+        assert_eq!(next_token, Some("abc".to_owned()));
+    }
+
+    #[test]
+    fn parses_last_metric_data_page() {
+        let document = "<GetMetricDataResult><MetricDataResults><member><Id>m3</Id>\
+            <StatusCode>Complete</StatusCode><Values><member>1</member></Values></member>\
+            </MetricDataResults></GetMetricDataResult>";
+        let (results, next_token) = parse_metric_data(document).unwrap();
+        assert_eq!(results, vec![("m3".to_owned(), Some(1.0))]);
+        assert_eq!(next_token, None);
+    }
+
+    #[test]
+    fn fails_on_forbidden_metric_data_query() {
+        let document = "<MetricDataResults><member><Id>m0</Id><StatusCode>Forbidden</StatusCode>\
+            <Values/></member></MetricDataResults>";
+        assert!(parse_metric_data(document).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use std::env;
+use std::cell::Cell;
+use std::cmp::min;
+use std::io::{self, Read, Write, Error as IoError, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use prometheus::Collector;
+use hyper;
+use hyper::Client;
+use hyper::client::pool::{Pool, Config as PoolConfig};
+use hyper::net::{NetworkConnector, NetworkStream, HttpStream, HttpsStream, SslClient};
+use hyper_native_tls::{NativeTlsClient, TlsStream};
+use rusoto::{DispatchSignedRequest, SignedRequest, HttpResponse, HttpDispatchError, Region};
+use url::Url;
+use config::HttpClientSettings;
+use aws_poller::{AwsPollerError, PollerResult};
+use deadline::{self, DEADLINE_EXCEEDED};
+use credentials::CredentialsProviderWrapper;
+use rate_limiter::{RateLimiters, RateLimiter};
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_WRITE_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_MAX_IDLE_CONNECTIONS: usize = 5;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 120;
+
+/// Message of I/O errors caused by a socket timeout.
+pub const SOCKET_TIMED_OUT: &'static str = "socket timed out";
+
+/// Creates HTTP clients for pollers, sharing rate limiters between pollers with the same credentials
+/// and region.
+pub struct HttpClientFactory {
+    settings: HttpClientSettings,
+    rate_limiters: RateLimiters
+}
+
+impl HttpClientFactory {
+    /// `settings` are expected to be validated, e.g. to have a positive rate limit.
+    pub fn new(settings: HttpClientSettings) -> PollerResult<HttpClientFactory> {
+        Ok(HttpClientFactory {
+            rate_limiters: RateLimiters::new(settings.requests_per_second)?,
+            settings: settings
+        })
+    }
+
+    /// Clients of the same credentials and region share the rate limiter.
+    pub fn client(&self, endpoint_url: Option<&str>, credentials_provider: &CredentialsProviderWrapper,
+                  region: Region) -> PollerResult<SharedHttpClient> {
+        SharedHttpClient::new(endpoint_url, &self.settings,
+                              self.rate_limiters.get(&credentials_provider.key(), &credentials_provider.label(),
+                                                     &region.to_string()))
+    }
+
+    /// Client for credentials requests, which are rare and not rate limited.
+    pub fn credentials_client(&self, endpoint_url: Option<&str>) -> PollerResult<SharedHttpClient> {
+        SharedHttpClient::new(endpoint_url, &self.settings, None)
+    }
+
+    pub fn counters(&self) -> Box<Collector> {
+        self.rate_limiters.counters()
+    }
+}
+
+/// HTTP client shared by all AWS clients of a poller. Connections are kept alive between polls,
+/// so AWS clients are cheap to create for every request.
+#[derive(Clone)]
+pub struct SharedHttpClient {
+    inner: Arc<Client>,
+    request_timeout: Duration,
+    rate_limiter: Option<RateLimiter>
+}
+
+impl SharedHttpClient {
+    fn new(endpoint_url: Option<&str>, settings: &HttpClientSettings, rate_limiter: Option<RateLimiter>)
+           -> PollerResult<SharedHttpClient> {
+        let connector = AwsConnector::new(endpoint_url, Duration::from_secs(
+            settings.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS)))?;
+        let pool = Pool::with_connector(PoolConfig {
+            max_idle: settings.max_idle_connections.unwrap_or(DEFAULT_MAX_IDLE_CONNECTIONS)
+        }, connector);
+        let mut client = Client::with_connector(pool);
+        client.set_read_timeout(Some(Duration::from_secs(
+            settings.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT_SECS))));
+        client.set_write_timeout(Some(Duration::from_secs(
+            settings.write_timeout.unwrap_or(DEFAULT_WRITE_TIMEOUT_SECS))));
+        Ok(SharedHttpClient {
+            inner: Arc::new(client),
+            request_timeout: Duration::from_secs(
+                settings.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS)),
+            rate_limiter: rate_limiter
+        })
+    }
+}
+
+impl DispatchSignedRequest for SharedHttpClient {
+    /// Every API call runs under its own deadline, which is enforced by `DeadlineStream`.
+    /// Waiting for the rate limiter counts towards the poll deadline only.
+    fn dispatch(&self, request: &SignedRequest) -> Result<HttpResponse, HttpDispatchError> {
+        if let Some(ref limiter) = self.rate_limiter {
+            limiter.acquire();
+        }
+        deadline::with_deadline(Instant::now() + self.request_timeout, || self.inner.dispatch(request))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Address {
+    scheme: String,
+    host: String,
+    port: u16
+}
+
+impl Address {
+    fn parse(url: &str) -> Option<Address> {
+        let url = match Url::parse(url) {
+            Ok(u) => u,
+            Err(_) => return None
+        };
+        match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => Some(Address {
+                scheme: url.scheme().to_owned(),
+                host: host.to_owned(),
+                port: port
+            }),
+            _ => None
+        }
+    }
+}
+
+/// Connects to AWS APIs directly or through the proxy from `HTTPS_PROXY`, optionally sending
+/// requests to a custom endpoint (VPC interface endpoint, local emulator) instead of the regional
+/// one. The `Host` header is left intact, so that request signatures remain valid.
+#[derive(Clone)]
+pub struct AwsConnector {
+    endpoint: Option<Address>,
+    proxy: Option<Address>,
+    no_proxy: Vec<String>,
+    connect_timeout: Duration,
+    tls: NativeTlsClient
+}
+
+impl AwsConnector {
+    pub fn new(endpoint_url: Option<&str>, connect_timeout: Duration) -> PollerResult<AwsConnector> {
+        let endpoint = match endpoint_url {
+            Some(url) => Some(Address::parse(url)
+                .ok_or(AwsPollerError::InvalidConfiguration(format!("Invalid endpoint URL {}", url)))?),
+            None => None
+        };
+        let proxy = match env::var("HTTPS_PROXY").or(env::var("https_proxy")) {
+            Ok(url) => Some(Address::parse(&url)
+                .ok_or(AwsPollerError::InvalidConfiguration(format!("Invalid HTTPS_PROXY {}", url)))?),
+            Err(_) => None
+        };
+        // the tunnel is opened over plain TCP, so TLS to the proxy itself is not supported
+        if let Some(ref p) = proxy {
+            if p.scheme != "http" {
+                return Err(AwsPollerError::InvalidConfiguration(
+                    format!("Unsupported HTTPS_PROXY scheme {}, only http:// proxies are supported", p.scheme)));
+            }
+        }
+        let no_proxy = env::var("NO_PROXY").or(env::var("no_proxy")).unwrap_or(String::new())
+            .split(',')
+            .map(|h| h.trim().trim_left_matches('.').to_owned())
+            .filter(|h| !h.is_empty())
+            .collect();
+        Ok(AwsConnector {
+            endpoint: endpoint,
+            proxy: proxy,
+            no_proxy: no_proxy,
+            connect_timeout: connect_timeout,
+            tls: NativeTlsClient::new()
+                .map_err(|e| AwsPollerError::NetworkError(format!("Could not initialize TLS: {}", e)))?
+        })
+    }
+
+    /// Tries all resolved addresses of the host, each one within the connect timeout.
+    fn connect_tcp(&self, host: &str, port: u16) -> hyper::Result<HttpStream> {
+        let mut last_err = IoError::new(ErrorKind::NotFound, format!("Could not resolve {}", host));
+        for addr in (host, port).to_socket_addrs()? {
+            let timeout = limit_by_deadline(Some(self.connect_timeout))?.unwrap_or(self.connect_timeout);
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(s) => return Ok(HttpStream(s)),
+                Err(e) => last_err = mark_timeout(e)
+            }
+        }
+        Err(hyper::Error::Io(last_err))
+    }
+
+    fn bypass_proxy(&self, host: &str) -> bool {
+        self.no_proxy.iter().any(|h| h == "*" || host == h || host.ends_with(&format!(".{}", h)))
+    }
+
+    /// Opens a tunnel to `target` with HTTP `CONNECT` method.
+    fn connect_through_proxy(&self, proxy: &Address, target: &Address) -> hyper::Result<HttpStream> {
+        let mut stream = self.connect_tcp(&proxy.host, proxy.port)?;
+        write!(stream, "CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n\r\n", target.host, target.port)?;
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        // read byte by byte not to consume anything beyond the proxy response headers
+        while !response.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte)? == 0 {
+                return Err(hyper::Error::Io(IoError::new(ErrorKind::UnexpectedEof,
+                                                         "Proxy closed connection")));
+            }
+            response.push(byte[0]);
+        }
+        let status_line = String::from_utf8_lossy(&response).lines().next().unwrap_or("").to_owned();
+        match status_line.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(stream),
+            _ => Err(hyper::Error::Io(IoError::new(ErrorKind::ConnectionRefused,
+                                                   format!("Proxy refused to connect: {}", status_line))))
+        }
+    }
+}
+
+impl NetworkConnector for AwsConnector {
+    type Stream = DeadlineStream<HttpsStream<TlsStream<HttpStream>>>;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<Self::Stream> {
+        let target = self.endpoint.clone().unwrap_or(Address {
+            scheme: scheme.to_owned(),
+            host: host.to_owned(),
+            port: port
+        });
+        let stream = match self.proxy {
+            Some(ref proxy) if !self.bypass_proxy(&target.host) =>
+                self.connect_through_proxy(proxy, &target)?,
+            _ => self.connect_tcp(&target.host, target.port)?
+        };
+        let stream = if target.scheme == "https" {
+            HttpsStream::Https(self.tls.wrap_client(stream, &target.host)?)
+        } else {
+            HttpsStream::Http(stream)
+        };
+        Ok(DeadlineStream::new(stream))
+    }
+}
+
+/// Socket timeouts are reported as `TimedOut` or `WouldBlock` depending on the platform. Both are
+/// replaced with an error of a known message, as only the message survives the conversion to
+/// `HttpDispatchError`.
+fn mark_timeout(e: IoError) -> IoError {
+    match e.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => IoError::new(ErrorKind::TimedOut, SOCKET_TIMED_OUT),
+        _ => e
+    }
+}
+
+/// Shortens the socket timeout to the deadline of the current thread, fails if it is exceeded.
+fn limit_by_deadline(timeout: Option<Duration>) -> io::Result<Option<Duration>> {
+    match deadline::remaining() {
+        None => Ok(timeout),
+        Some(r) if r == Duration::from_secs(0) => Err(IoError::new(ErrorKind::TimedOut, DEADLINE_EXCEEDED)),
+        Some(r) => Ok(Some(timeout.map_or(r, |t| min(t, r))))
+    }
+}
+
+/// Stream which does not block past the deadline of the thread using it, so that a hung API call
+/// is abandoned when the request timeout or the poll deadline is hit.
+pub struct DeadlineStream<S: NetworkStream> {
+    inner: S,
+    read_timeout: Cell<Option<Duration>>,
+    write_timeout: Cell<Option<Duration>>
+}
+
+impl<S: NetworkStream> DeadlineStream<S> {
+    fn new(inner: S) -> DeadlineStream<S> {
+        DeadlineStream {
+            inner: inner,
+            read_timeout: Cell::new(None),
+            write_timeout: Cell::new(None)
+        }
+    }
+}
+
+impl<S: NetworkStream> Read for DeadlineStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = limit_by_deadline(self.read_timeout.get())?;
+        self.inner.set_read_timeout(timeout)?;
+        self.inner.read(buf).map_err(mark_timeout)
+    }
+}
+
+impl<S: NetworkStream> Write for DeadlineStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let timeout = limit_by_deadline(self.write_timeout.get())?;
+        self.inner.set_write_timeout(timeout)?;
+        self.inner.write(buf).map_err(mark_timeout)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: NetworkStream> NetworkStream for DeadlineStream<S> {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.read_timeout.set(dur);
+        self.inner.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.write_timeout.set(dur);
+        self.inner.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: ::std::net::Shutdown) -> io::Result<()> {
+        self.inner.close(how)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_socket_timeouts() {
+        for kind in vec![ErrorKind::TimedOut, ErrorKind::WouldBlock] {
+            let e = mark_timeout(IoError::new(kind, "Resource temporarily unavailable"));
+            assert_eq!(e.kind(), ErrorKind::TimedOut);
+            assert_eq!(e.to_string(), SOCKET_TIMED_OUT);
+        }
+        let e = mark_timeout(IoError::new(ErrorKind::ConnectionReset, "reset"));
+        assert_eq!(e.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn parses_addresses_with_default_ports() {
+        let proxy = Address::parse("http://proxy.local:3128").unwrap();
+        assert_eq!((proxy.scheme.as_str(), proxy.host.as_str(), proxy.port), ("http", "proxy.local", 3128));
+        let endpoint = Address::parse("https://vpce-1.ec2.us-east-1.vpce.amazonaws.com").unwrap();
+        assert_eq!((endpoint.scheme.as_str(), endpoint.port), ("https", 443));
+        assert!(Address::parse("proxy.local:3128:x").is_none());
+    }
+}
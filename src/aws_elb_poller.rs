@@ -0,0 +1,448 @@
+use config::AwsElbPollerSettingsProvider;
+use std::str::FromStr;
+use std::io::{stderr, Write};
+use std::collections::HashMap;
+use rusoto::Region;
+use rusoto::elbv2;
+use prometheus::{Opts, GaugeVec, Collector};
+use pagination::{PaginatedIterator, PaginatedRequestor};
+use poller::{Poller, CollectorGroup};
+use aws_poller::{AwsPollerError, PollerResult, current_series, set_series,
+                 remove_stale_series};
+use credentials::CredentialsProviderWrapper;
+use aws_connector::{HttpClientFactory, SharedHttpClient};
+
+impl_from_aws_error!(elbv2, DescribeLoadBalancersError, "DescribeLoadBalancers");
+impl_from_aws_error!(elbv2, DescribeTargetGroupsError, "DescribeTargetGroups");
+impl_from_aws_error!(elbv2, DescribeTargetHealthError, "DescribeTargetHealth");
+
+type ElbClient = elbv2::ElbClient<CredentialsProviderWrapper, SharedHttpClient>;
+
+pub struct AwsElbPoller {
+    credentials_provider: CredentialsProviderWrapper,
+    region: Region,
+    http_client: SharedHttpClient,
+    max_chunk_size: Option<i64>,
+    load_balancers: GaugeVec,
+    target_groups: GaugeVec,
+    target_health: GaugeVec
+}
+
+impl AwsElbPoller {
+    /// Returns `None` if the poller is not configured.
+    pub fn new(settings_provider: &AwsElbPollerSettingsProvider,
+               http_clients: &HttpClientFactory) -> PollerResult<Option<Self>> {
+        let settings = match settings_provider.aws_elb_poller_settings() {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        let region = Region::from_str(&settings.region)?;
+        let credentials_provider = CredentialsProviderWrapper::from_type(
+            "elb", settings.credentials_provider, settings.profile_name, settings.credentials_file,
+            region, http_clients)?;
+        let http_client = http_clients.client(settings.endpoint_url.as_ref().map(|s| &**s),
+                                              &credentials_provider, region)?;
+        let result = AwsElbPoller {
+            credentials_provider: credentials_provider,
+            region: region,
+            http_client: http_client,
+            max_chunk_size: settings.max_chunk_size,
+            load_balancers: GaugeVec::new(
+                Opts::new("aws_load_balancer_state", "Identifies a state of AWS application or network load balancer"),
+                &["arn", "name", "type", "scheme", "state"])?,
+            target_groups: GaugeVec::new(
+                Opts::new("aws_target_group", "Identifies an AWS target group attached to a load balancer"),
+                &["arn", "name", "load_balancer", "protocol", "port"])?,
+            target_health: GaugeVec::new(
+                Opts::new("aws_target_health", "Identifies a health state of an AWS target group member"),
+                &["target_group", "load_balancer", "id", "port", "state", "reason"])?,
+        };
+        Ok(Some(result))
+    }
+
+    fn get_elb_client(&self) -> ElbClient {
+        ElbClient::new(self.http_client.clone(), self.credentials_provider.clone(), self.region)
+    }
+
+    /// Elastic Load Balancing API does not support dry run, so a single load balancer is requested instead.
+    fn test_describe_load_balancers(&self) -> Option<AwsPollerError> {
+        let client = self.get_elb_client();
+        let mut req: elbv2::DescribeLoadBalancersInput = Default::default();
+        req.page_size = Some(1);
+        client.describe_load_balancers(&req).err().map(AwsPollerError::from)
+    }
+
+    /// Returns load balancer names by ARN.
+    fn poll_load_balancers(&self, client: &ElbClient) -> PollerResult<HashMap<String, String>> {
+        let mut names = HashMap::new();
+        let mut stale = current_series(&self.load_balancers);
+        let mut query_err = None;
+        {
+            let load_balancers = PaginatedIterator::new(
+                DescribeLoadBalancersRequestor::new(client, self.max_chunk_size), &mut query_err);
+            for lb in load_balancers {
+                let labels = load_balancer_labels(lb);
+                set_series(&self.load_balancers, &mut stale, &labels, 1.0);
+                names.insert(labels[0].1.clone(), labels[1].1.clone());
+            }
+        }
+        if let Some(e) = query_err {
+            return Err(AwsPollerError::from(e));
+        }
+        remove_stale_series(&self.load_balancers, &stale);
+        Ok(names)
+    }
+
+    fn poll_target_groups(&self, client: &ElbClient, load_balancer_names: &HashMap<String, String>)
+                          -> PollerResult<()> {
+        let mut query_err = None;
+        let target_groups: Vec<elbv2::TargetGroup> = PaginatedIterator::new(
+            DescribeTargetGroupsRequestor::new(client, self.max_chunk_size), &mut query_err).collect();
+        let groups = target_groups.into_iter()
+            .map(|tg| {
+                let req = elbv2::DescribeTargetHealthInput {
+                    target_group_arn: tg.target_group_arn.clone().unwrap_or(String::new()),
+                    ..Default::default()
+                };
+                let health = client.describe_target_health(&req)
+                    .map(|resp| resp.target_health_descriptions.unwrap_or(Vec::new()))
+                    .map_err(AwsPollerError::from);
+                (tg, health)
+            })
+            .collect();
+        let result = set_target_groups(&self.target_groups, &self.target_health, groups, load_balancer_names,
+                                       query_err.is_none());
+        if let Some(e) = query_err {
+            return Err(AwsPollerError::from(e));
+        }
+        result
+    }
+}
+
+fn load_balancer_labels(lb: elbv2::LoadBalancer) -> Vec<(String, String)> {
+    vec![
+        ("arn".to_owned(), lb.load_balancer_arn.unwrap_or(String::new())),
+        ("name".to_owned(), lb.load_balancer_name.unwrap_or(String::new())),
+        ("type".to_owned(), lb.type_.unwrap_or(String::new())),
+        ("scheme".to_owned(), lb.scheme.unwrap_or(String::new())),
+        ("state".to_owned(), lb.state.and_then(|s| s.code).unwrap_or(String::new()))
+    ]
+}
+
+/// Load balancers of the target group are identified by their names, or ARNs if they are unknown.
+fn target_group_labels(tg: elbv2::TargetGroup, load_balancer_names: &HashMap<String, String>)
+                       -> Vec<(String, String)> {
+    let load_balancer = tg.load_balancer_arns.unwrap_or(Vec::new()).iter()
+        .map(|a| load_balancer_names.get(a).cloned().unwrap_or(a.clone()))
+        .collect::<Vec<_>>()
+        .join(",");
+    vec![
+        ("arn".to_owned(), tg.target_group_arn.unwrap_or(String::new())),
+        ("name".to_owned(), tg.target_group_name.unwrap_or(String::new())),
+        ("load_balancer".to_owned(), load_balancer),
+        ("protocol".to_owned(), tg.protocol.unwrap_or(String::new())),
+        ("port".to_owned(), tg.port.map(|p| p.to_string()).unwrap_or(String::new()))
+    ]
+}
+
+/// Returns `None` for a description without a target.
+fn target_health_labels(group_labels: &Vec<(String, String)>, d: elbv2::TargetHealthDescription)
+                        -> Option<Vec<(String, String)>> {
+    let label = |name: &str| group_labels.iter().find(|l| l.0 == name).map(|l| l.1.clone()).unwrap_or(String::new());
+    let (id, port) = match d.target {
+        Some(t) => (t.id, t.port.map(|p| p.to_string()).unwrap_or(String::new())),
+        None => return None
+    };
+    let (state, reason) = match d.target_health {
+        Some(h) => (h.state.unwrap_or(String::new()), h.reason.unwrap_or(String::new())),
+        None => (String::new(), String::new())
+    };
+    Some(vec![
+        ("target_group".to_owned(), label("name")),
+        ("load_balancer".to_owned(), label("load_balancer")),
+        ("id".to_owned(), id),
+        ("port".to_owned(), port),
+        ("state".to_owned(), state),
+        ("reason".to_owned(), reason)
+    ])
+}
+
+/// Sets series of the target groups and the health of their members. Series which disappeared are
+/// removed only if all target groups were `complete`ly listed, target health series are kept
+/// as well if the health of any target group could not be described, and the error is returned.
+fn set_target_groups(target_groups: &GaugeVec, target_health: &GaugeVec,
+                     groups: Vec<(elbv2::TargetGroup, PollerResult<Vec<elbv2::TargetHealthDescription>>)>,
+                     load_balancer_names: &HashMap<String, String>, complete: bool) -> PollerResult<()> {
+    let mut stale_groups = current_series(target_groups);
+    let mut stale_health = current_series(target_health);
+    let mut health_err = None;
+    for (tg, health) in groups {
+        let labels = target_group_labels(tg, load_balancer_names);
+        set_series(target_groups, &mut stale_groups, &labels, 1.0);
+        let descriptions = match health {
+            Ok(d) => d,
+            Err(e) => { health_err = Some(e); continue; }
+        };
+        for d in descriptions {
+            if let Some(health_labels) = target_health_labels(&labels, d) {
+                set_series(target_health, &mut stale_health, &health_labels, 1.0);
+            }
+        }
+    }
+    if !complete {
+        return Ok(());
+    }
+    remove_stale_series(target_groups, &stale_groups);
+    if let Some(e) = health_err {
+        return Err(e);
+    }
+    remove_stale_series(target_health, &stale_health);
+    Ok(())
+}
+
+impl Poller for AwsElbPoller {
+    fn name(&self) -> &str {
+        "elb"
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        self.credentials_provider.test().or_else(|| self.test_describe_load_balancers())
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let client = self.get_elb_client();
+        let result = self.poll_load_balancers(&client)
+            .and_then(|names| self.poll_target_groups(&client, &names));
+        if let Err(ref e) = result {
+            let _ = writeln!(&mut stderr(), "Unexpected error during load balancers enumeration: {:?}", e);
+            self.credentials_provider.revalidate_on(e);
+        }
+        result
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(vec![
+            Box::new(self.load_balancers.clone()),
+            Box::new(self.target_groups.clone()),
+            Box::new(self.target_health.clone()),
+            self.credentials_provider.counters(),
+        ]))
+    }
+}
+
+struct DescribeLoadBalancersRequestor<'a> {
+    client: &'a ElbClient,
+    req: elbv2::DescribeLoadBalancersInput,
+    first_chunk: bool
+}
+
+impl<'a> PaginatedRequestor for DescribeLoadBalancersRequestor<'a> {
+    type Item = elbv2::LoadBalancer;
+    type Error = elbv2::DescribeLoadBalancersError;
+    fn next_page(&mut self) -> Result<Option<Vec<Self::Item>>, Self::Error> {
+        if self.req.marker.is_none() && !self.first_chunk {
+            return Ok(None);
+        }
+        self.first_chunk = false;
+        match self.client.describe_load_balancers(&self.req) {
+            Ok(resp) => {
+                self.req.marker = resp.next_marker.clone();
+                Ok(resp.load_balancers)
+            }
+            Err(e) => {
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'a> DescribeLoadBalancersRequestor<'a> {
+    fn new(client: &'a ElbClient, chunk_size: Option<i64>) -> Self {
+        let mut req: elbv2::DescribeLoadBalancersInput = Default::default();
+        req.page_size = chunk_size;
+        DescribeLoadBalancersRequestor {
+            client: client,
+            req: req,
+            first_chunk: true,
+        }
+    }
+}
+
+struct DescribeTargetGroupsRequestor<'a> {
+    client: &'a ElbClient,
+    req: elbv2::DescribeTargetGroupsInput,
+    first_chunk: bool
+}
+
+impl<'a> PaginatedRequestor for DescribeTargetGroupsRequestor<'a> {
+    type Item = elbv2::TargetGroup;
+    type Error = elbv2::DescribeTargetGroupsError;
+    fn next_page(&mut self) -> Result<Option<Vec<Self::Item>>, Self::Error> {
+        if self.req.marker.is_none() && !self.first_chunk {
+            return Ok(None);
+        }
+        self.first_chunk = false;
+        match self.client.describe_target_groups(&self.req) {
+            Ok(resp) => {
+                self.req.marker = resp.next_marker.clone();
+                Ok(resp.target_groups)
+            }
+            Err(e) => {
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'a> DescribeTargetGroupsRequestor<'a> {
+    fn new(client: &'a ElbClient, chunk_size: Option<i64>) -> Self {
+        let mut req: elbv2::DescribeTargetGroupsInput = Default::default();
+        req.page_size = chunk_size;
+        DescribeTargetGroupsRequestor {
+            client: client,
+            req: req,
+            first_chunk: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target_group(arn: &str, name: &str) -> elbv2::TargetGroup {
+        elbv2::TargetGroup {
+            target_group_arn: Some(arn.to_owned()),
+            target_group_name: Some(name.to_owned()),
+            load_balancer_arns: Some(vec!["arn:lb/web".to_owned()]),
+            protocol: Some("HTTP".to_owned()),
+            port: Some(80),
+            ..Default::default()
+        }
+    }
+
+    fn healthy_target(id: &str) -> elbv2::TargetHealthDescription {
+        elbv2::TargetHealthDescription {
+            target: Some(elbv2::TargetDescription {
+                id: id.to_owned(),
+                port: Some(8080),
+                ..Default::default()
+            }),
+            target_health: Some(elbv2::TargetHealth {
+                state: Some("healthy".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn gauges() -> (GaugeVec, GaugeVec) {
+        let target_groups = GaugeVec::new(Opts::new("target_group", "target group"),
+                                          &["arn", "name", "load_balancer", "protocol", "port"]).unwrap();
+        let target_health = GaugeVec::new(Opts::new("target_health", "target health"),
+                                          &["target_group", "load_balancer", "id", "port", "state", "reason"])
+            .unwrap();
+        (target_groups, target_health)
+    }
+
+    fn names() -> HashMap<String, String> {
+        let mut names = HashMap::new();
+        names.insert("arn:lb/web".to_owned(), "web".to_owned());
+        names
+    }
+
+    fn ids(gauges: &GaugeVec, label: &str) -> Vec<String> {
+        let mut ids: Vec<String> = current_series(gauges).into_iter().map(|s| s[label].clone()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn builds_load_balancer_labels() {
+        let lb = elbv2::LoadBalancer {
+            load_balancer_arn: Some("arn:lb/web".to_owned()),
+            load_balancer_name: Some("web".to_owned()),
+            type_: Some("application".to_owned()),
+            state: Some(elbv2::LoadBalancerState { code: Some("active".to_owned()), ..Default::default() }),
+            ..Default::default()
+        };
+        assert_eq!(load_balancer_labels(lb), vec![
+            ("arn".to_owned(), "arn:lb/web".to_owned()),
+            ("name".to_owned(), "web".to_owned()),
+            ("type".to_owned(), "application".to_owned()),
+            ("scheme".to_owned(), String::new()),
+            ("state".to_owned(), "active".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn names_load_balancers_of_target_groups() {
+        let mut tg = target_group("arn:tg/api", "api");
+        tg.load_balancer_arns = Some(vec!["arn:lb/web".to_owned(), "arn:lb/unknown".to_owned()]);
+        assert_eq!(target_group_labels(tg, &names()), vec![
+            ("arn".to_owned(), "arn:tg/api".to_owned()),
+            ("name".to_owned(), "api".to_owned()),
+            ("load_balancer".to_owned(), "web,arn:lb/unknown".to_owned()),
+            ("protocol".to_owned(), "HTTP".to_owned()),
+            ("port".to_owned(), "80".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn builds_target_health_labels() {
+        let group_labels = target_group_labels(target_group("arn:tg/api", "api"), &names());
+        assert_eq!(target_health_labels(&group_labels, healthy_target("i-1")), Some(vec![
+            ("target_group".to_owned(), "api".to_owned()),
+            ("load_balancer".to_owned(), "web".to_owned()),
+            ("id".to_owned(), "i-1".to_owned()),
+            ("port".to_owned(), "8080".to_owned()),
+            ("state".to_owned(), "healthy".to_owned()),
+            ("reason".to_owned(), String::new())
+        ]));
+        assert_eq!(target_health_labels(&group_labels, Default::default()), None);
+    }
+
+    #[test]
+    fn removes_stale_target_groups_and_health() {
+        let (target_groups, target_health) = gauges();
+        set_target_groups(&target_groups, &target_health,
+                          vec![(target_group("arn:tg/api", "api"), Ok(vec![healthy_target("i-1")])),
+                               (target_group("arn:tg/old", "old"), Ok(vec![healthy_target("i-2")]))],
+                          &names(), true).unwrap();
+        set_target_groups(&target_groups, &target_health,
+                          vec![(target_group("arn:tg/api", "api"), Ok(vec![healthy_target("i-3")]))],
+                          &names(), true).unwrap();
+        assert_eq!(ids(&target_groups, "name"), vec!["api"]);
+        assert_eq!(ids(&target_health, "id"), vec!["i-3"]);
+    }
+
+    #[test]
+    fn keeps_target_health_when_any_health_query_fails() {
+        let (target_groups, target_health) = gauges();
+        set_target_groups(&target_groups, &target_health,
+                          vec![(target_group("arn:tg/api", "api"), Ok(vec![healthy_target("i-1")])),
+                               (target_group("arn:tg/old", "old"), Ok(vec![healthy_target("i-2")]))],
+                          &names(), true).unwrap();
+        let result = set_target_groups(
+            &target_groups, &target_health,
+            vec![(target_group("arn:tg/api", "api"), Ok(vec![healthy_target("i-3")])),
+                 (target_group("arn:tg/web", "web"), Err(AwsPollerError::UnknownError("throttled".to_owned())))],
+            &names(), true);
+        assert!(result.is_err());
+        assert_eq!(ids(&target_groups, "name"), vec!["api", "web"]);
+        assert_eq!(ids(&target_health, "id"), vec!["i-1", "i-2", "i-3"]);
+    }
+
+    #[test]
+    fn keeps_all_series_when_target_groups_are_incomplete() {
+        let (target_groups, target_health) = gauges();
+        set_target_groups(&target_groups, &target_health,
+                          vec![(target_group("arn:tg/old", "old"), Ok(vec![healthy_target("i-2")]))],
+                          &names(), true).unwrap();
+        set_target_groups(&target_groups, &target_health,
+                          vec![(target_group("arn:tg/api", "api"), Ok(vec![healthy_target("i-1")]))],
+                          &names(), false).unwrap();
+        assert_eq!(ids(&target_groups, "name"), vec!["api", "old"]);
+        assert_eq!(ids(&target_health, "id"), vec!["i-1", "i-2"]);
+    }
+}
@@ -1,23 +1,31 @@
-use config::{AwsInstancesPollerSettingsProvider, AwsSpotPricesPollerSettingsProvider,
-             AwsCredentialsProviderType};
+use config::{AwsCredentialsProviderType, AwsInstancesPollerSettings, AwsInstancesPollerSettingsProvider, AwsSpotPricesPollerSettingsProvider,
+             AwsSpotRequestsPollerSettingsProvider, AwsAccountLimitsPollerSettingsProvider,
+             AwsInstanceStatusPollerSettingsProvider};
 use std::result::Result as StdResult;
 use std::error::Error as StdError;
 use std::fmt;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::io::{stderr, Write};
-use rusoto::{ProvideAwsCredentials, AwsCredentials, DefaultCredentialsProviderSync, EnvironmentProvider,
-             ProfileProvider, InstanceMetadataProvider, ContainerProvider, CredentialsError,
-             Region, ParseRegionError, HttpDispatchError};
+use rusoto::{CredentialsError, Region, ParseRegionError, HttpDispatchError, SignedRequest};
 use rusoto::ec2;
-use rusoto::default_tls_client;
 use std::ascii::AsciiExt;
 use std::iter::{Iterator, IntoIterator};
 use prometheus::{Opts, GaugeVec, Collector};
 use prometheus::Error as PrometheusError;
 use std::collections::HashMap;
 use pagination::{PaginatedIterator, PaginatedRequestor};
-use poller::Poller;
+use poller::{Poller, CollectorGroup, SnapshotCollector};
+use instance_types::InstanceTypeCatalogue;
+use credentials::CredentialsProviderWrapper;
+use deadline::DEADLINE_EXCEEDED;
+use aws_connector::{HttpClientFactory, SharedHttpClient, SOCKET_TIMED_OUT};
+use fan_out::fan_out;
+use aws_request;
+use serde_json;
+use chrono::{DateTime, NaiveDateTime, Timelike};
+
+pub const DEFAULT_MAX_PARALLEL_REQUESTS: usize = 4;
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum AwsPollerError {
@@ -25,6 +33,8 @@ pub enum AwsPollerError {
     InsufficientPermissions(String),
     BadRegion(String),
     NetworkError(String),
+    Timeout(String),
+    InvalidConfiguration(String),
     UnknownError(String),
     NoError
 }
@@ -49,7 +59,13 @@ impl From<ParseRegionError> for AwsPollerError {
 
 impl From<HttpDispatchError> for AwsPollerError {
     fn from(error: HttpDispatchError) -> Self {
-        AwsPollerError::NetworkError(String::from(error.description()))
+        let message = String::from(error.description());
+        // only the message of the I/O error is kept, timeouts are reported with known ones
+        if message == DEADLINE_EXCEEDED || message == SOCKET_TIMED_OUT {
+            AwsPollerError::Timeout(message)
+        } else {
+            AwsPollerError::NetworkError(message)
+        }
     }
 }
 
@@ -59,49 +75,82 @@ impl From<PrometheusError> for AwsPollerError {
     }
 }
 
-impl From<ec2::DescribeInstancesError> for AwsPollerError {
-    fn from(e: ec2::DescribeInstancesError) -> Self {
-        match e {
-            ec2::DescribeInstancesError::HttpDispatch(dpt) => AwsPollerError::from(dpt),
-            ec2::DescribeInstancesError::Credentials(crd) => AwsPollerError::from(crd),
-            ec2::DescribeInstancesError::Validation(s) => AwsPollerError::InvalidCredentials(s),
-            ec2::DescribeInstancesError::Unknown(s) => {
-                if s.contains("DryRunOperation") {
-                    AwsPollerError::NoError
-                } else if s.contains("UnauthorizedOperation") {
-                    AwsPollerError::InsufficientPermissions(String::from("DescribeInstances"))
-                } else if s.contains("AuthFailure") {
-                    AwsPollerError::InvalidCredentials(s)
-                } else {
-                    AwsPollerError::UnknownError(s)
+impl AwsPollerError {
+    /// Short error kind used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            AwsPollerError::InvalidCredentials(_) => "invalid_credentials",
+            AwsPollerError::InsufficientPermissions(_) => "insufficient_permissions",
+            AwsPollerError::BadRegion(_) => "bad_region",
+            AwsPollerError::NetworkError(_) => "network",
+            AwsPollerError::Timeout(_) => "timeout",
+            AwsPollerError::InvalidConfiguration(_) => "invalid_configuration",
+            AwsPollerError::UnknownError(_) => "unknown",
+            AwsPollerError::NoError => "none",
+        }
+    }
+
+    fn from_ec2_message(message: String, operation: &str) -> Self {
+        if message.contains("DryRunOperation") {
+            AwsPollerError::NoError
+        } else if message.contains("UnauthorizedOperation") {
+            AwsPollerError::InsufficientPermissions(String::from(operation))
+        } else if message.contains("AuthFailure") {
+            AwsPollerError::InvalidCredentials(message)
+        } else {
+            AwsPollerError::UnknownError(message)
+        }
+    }
+}
+
+/// Implements conversion of errors of query protocol services which report insufficient permissions
+/// as `AccessDenied`, service specific faults are reported as unknown errors.
+macro_rules! impl_from_aws_error {
+    ($service:ident, $error:ident, $operation:expr) => {
+        impl From<$service::$error> for AwsPollerError {
+            fn from(e: $service::$error) -> Self {
+                match e {
+                    $service::$error::HttpDispatch(dpt) => AwsPollerError::from(dpt),
+                    $service::$error::Credentials(crd) => AwsPollerError::from(crd),
+                    $service::$error::Validation(s) => AwsPollerError::InvalidCredentials(s),
+                    $service::$error::Unknown(s) => {
+                        if s.contains("AccessDenied") {
+                            AwsPollerError::InsufficientPermissions(String::from($operation))
+                        } else if s.contains("InvalidClientTokenId") || s.contains("SignatureDoesNotMatch") {
+                            AwsPollerError::InvalidCredentials(s)
+                        } else {
+                            AwsPollerError::UnknownError(s)
+                        }
+                    }
+                    e => AwsPollerError::UnknownError(String::from(::std::error::Error::description(&e)))
                 }
             }
         }
     }
 }
 
-impl From<ec2::DescribeSpotPriceHistoryError> for AwsPollerError {
-
-    fn from(e: ec2::DescribeSpotPriceHistoryError) -> Self {
-        match e {
-            ec2::DescribeSpotPriceHistoryError::HttpDispatch(dpt) => AwsPollerError::from(dpt),
-            ec2::DescribeSpotPriceHistoryError::Credentials(crd) => AwsPollerError::from(crd),
-            ec2::DescribeSpotPriceHistoryError::Validation(s) => AwsPollerError::InvalidCredentials(s),
-            ec2::DescribeSpotPriceHistoryError::Unknown(s) => {
-                if s.contains("DryRunOperation") {
-                    AwsPollerError::NoError
-                } else if s.contains("UnauthorizedOperation") {
-                    AwsPollerError::InsufficientPermissions(String::from("DescribeInstances"))
-                } else if s.contains("AuthFailure") {
-                    AwsPollerError::InvalidCredentials(s)
-                } else {
-                    AwsPollerError::UnknownError(s)
+macro_rules! impl_from_ec2_error {
+    ($error:ident, $operation:expr) => {
+        impl From<ec2::$error> for AwsPollerError {
+            fn from(e: ec2::$error) -> Self {
+                match e {
+                    ec2::$error::HttpDispatch(dpt) => AwsPollerError::from(dpt),
+                    ec2::$error::Credentials(crd) => AwsPollerError::from(crd),
+                    ec2::$error::Validation(s) => AwsPollerError::InvalidCredentials(s),
+                    ec2::$error::Unknown(s) => AwsPollerError::from_ec2_message(s, $operation)
                 }
             }
         }
     }
 }
 
+impl_from_ec2_error!(DescribeInstancesError, "DescribeInstances");
+impl_from_ec2_error!(DescribeSpotPriceHistoryError, "DescribeSpotPriceHistory");
+impl_from_ec2_error!(DescribeSpotInstanceRequestsError, "DescribeSpotInstanceRequests");
+impl_from_ec2_error!(DescribeSpotFleetRequestsError, "DescribeSpotFleetRequests");
+impl_from_ec2_error!(DescribeAccountAttributesError, "DescribeAccountAttributes");
+impl_from_ec2_error!(DescribeInstanceStatusError, "DescribeInstanceStatus");
+
 impl StdError for AwsPollerError {
     fn description(&self) -> &str {
         match *self {
@@ -109,90 +158,266 @@ impl StdError for AwsPollerError {
             AwsPollerError::InsufficientPermissions(ref m) => &m,
             AwsPollerError::BadRegion(ref m) => &m,
             AwsPollerError::NetworkError(ref m) => &m,
+            AwsPollerError::Timeout(ref m) => &m,
+            AwsPollerError::InvalidConfiguration(ref m) => &m,
             AwsPollerError::UnknownError(ref m) => &m,
             AwsPollerError::NoError => "No error",
         }
     }
 }
 
-type PollerResult<T> = StdResult<T, AwsPollerError>;
+pub type PollerResult<T> = StdResult<T, AwsPollerError>;
+
+type Ec2Client = ec2::Ec2Client<CredentialsProviderWrapper, SharedHttpClient>;
 
+/// Latest spot prices keyed by availability zone, platform, networking and instance type,
+/// shared between the spot prices poller and the pollers which need them.
 #[derive(Clone)]
-struct CredentialsProviderWrapper {
-    inner: Arc<ProvideAwsCredentials + Send + Sync>
-}
-
-impl CredentialsProviderWrapper {
-    fn from_type(provider_type: AwsCredentialsProviderType)
-                 -> Result<CredentialsProviderWrapper, CredentialsError> {
-        Ok(CredentialsProviderWrapper {
-            inner: match provider_type {
-                AwsCredentialsProviderType::Default =>
-                    Arc::new(DefaultCredentialsProviderSync::new()?),
-                AwsCredentialsProviderType::Environment =>
-                    Arc::new(EnvironmentProvider {}),
-                AwsCredentialsProviderType::Profile =>
-                    Arc::new(ProfileProvider::new()?),
-                AwsCredentialsProviderType::Instance =>
-                    Arc::new(InstanceMetadataProvider {}),
-                AwsCredentialsProviderType::Container =>
-                    Arc::new(ContainerProvider {})
-            }
+pub struct SpotPriceTable {
+    prices: Arc<RwLock<HashMap<(String, String, String, String), f64>>>
+}
+
+impl SpotPriceTable {
+    pub fn new() -> SpotPriceTable {
+        SpotPriceTable {
+            prices: Arc::new(RwLock::new(HashMap::new()))
+        }
+    }
+
+    fn get(&self, availability_zone: &str, platform: &str, networking: &str, instance_type: &str) -> Option<f64> {
+        let key = (availability_zone.to_owned(), platform.to_owned(), networking.to_owned(), instance_type.to_owned());
+        self.prices.read().unwrap().get(&key).cloned()
+    }
+
+    fn set(&self, availability_zone: &str, platform: &str, networking: &str, instance_type: &str, price: f64) {
+        let key = (availability_zone.to_owned(), platform.to_owned(), networking.to_owned(), instance_type.to_owned());
+        self.prices.write().unwrap().insert(key, price);
+    }
+}
+
+/// Running instance with the name of the account and the region it was discovered in.
+#[derive(Clone)]
+pub struct InventoryInstance {
+    pub account: String,
+    pub region: Region,
+    pub labels: Vec<(String, String)>
+}
+
+/// Running instances discovered during the last successful poll of `AwsInstancesPoller`.
+#[derive(Clone)]
+pub struct InstanceInventory {
+    instances: Arc<RwLock<Vec<InventoryInstance>>>
+}
+
+impl InstanceInventory {
+    pub fn new() -> InstanceInventory {
+        InstanceInventory {
+            instances: Arc::new(RwLock::new(Vec::new()))
+        }
+    }
+
+    pub fn instances(&self) -> Vec<InventoryInstance> {
+        self.instances.read().unwrap().clone()
+    }
+
+    fn publish(&self, instances: Vec<InventoryInstance>) {
+        *self.instances.write().unwrap() = instances;
+    }
+}
+
+/// Name of the account polled with the credentials of the instances poller itself.
+const DEFAULT_ACCOUNT_NAME: &'static str = "default";
+
+/// Credentials and HTTP clients used to poll one AWS account, one client per polled region.
+#[derive(Clone)]
+pub struct AwsAccount {
+    pub name: String,
+    pub credentials_provider: CredentialsProviderWrapper,
+    http_clients: Vec<(Region, SharedHttpClient)>
+}
+
+impl AwsAccount {
+    /// Web identity tokens are exchanged in the first of `regions`.
+    fn new(name: &str, poller_name: &str, provider_type: Option<AwsCredentialsProviderType>,
+           profile_name: Option<String>, credentials_file: Option<String>, regions: &Vec<Region>,
+           endpoint_url: Option<&str>, http_clients: &HttpClientFactory) -> PollerResult<AwsAccount> {
+        let credentials_provider = CredentialsProviderWrapper::from_type(
+            poller_name, provider_type, profile_name, credentials_file, regions[0], http_clients)?;
+        let clients = regions.iter()
+            .map(|&r| http_clients.client(endpoint_url, &credentials_provider, r).map(|c| (r, c)))
+            .collect::<PollerResult<Vec<_>>>()?;
+        Ok(AwsAccount {
+            name: name.to_owned(),
+            credentials_provider: credentials_provider,
+            http_clients: clients
         })
     }
 
-    /// Try to retrieve credentials from provider to be able to fail-fast if credentials
-    /// are not available.
-    fn test(&self) -> Option<AwsPollerError> {
-        self.credentials().err().map(|e| AwsPollerError::from(e))
+    pub fn http_client(&self, region: Region) -> SharedHttpClient {
+        self.http_clients.iter()
+            .find(|c| c.0 == region)
+            .expect("HTTP client of a polled region")
+            .1.clone()
+    }
+
+    fn ec2_client(&self, region: Region) -> Ec2Client {
+        Ec2Client::new(self.http_client(region), self.credentials_provider.clone(), region)
+    }
+}
+
+/// Regions instances are polled in, either `regions` or the single `region`.
+pub fn instance_regions(settings: &AwsInstancesPollerSettings) -> PollerResult<Vec<Region>> {
+    let regions = settings.regions.clone().unwrap_or(vec![settings.region.clone()]).iter()
+        .map(|r| Region::from_str(r))
+        .collect::<StdResult<Vec<_>, _>>()?;
+    if regions.is_empty() {
+        return Err(AwsPollerError::BadRegion("No regions to poll instances in".to_owned()));
     }
+    Ok(regions)
 }
 
-impl ProvideAwsCredentials for CredentialsProviderWrapper {
-    fn credentials(&self) -> StdResult<AwsCredentials, CredentialsError> {
-        self.inner.credentials()
+/// Accounts instances are polled in, either `accounts` or the default one using the credentials
+/// of the instances poller. Credentials counters are reported as `poller_name/account`.
+pub fn instance_accounts(poller_name: &str, settings: &AwsInstancesPollerSettings, regions: &Vec<Region>,
+                         http_clients: &HttpClientFactory) -> PollerResult<Vec<AwsAccount>> {
+    let endpoint_url = settings.endpoint_url.as_ref().map(|s| &**s);
+    match settings.accounts {
+        Some(ref accounts) => accounts.iter()
+            .map(|a| AwsAccount::new(&a.name, &format!("{}/{}", poller_name, a.name), a.credentials_provider.clone(),
+                                     a.profile_name.clone(), a.credentials_file.clone(), regions, endpoint_url,
+                                     http_clients))
+            .collect(),
+        None => Ok(vec![AwsAccount::new(DEFAULT_ACCOUNT_NAME, poller_name, settings.credentials_provider.clone(),
+                                        settings.profile_name.clone(), settings.credentials_file.clone(),
+                                        regions, endpoint_url, http_clients)?])
     }
 }
 
-type Ec2Client = ec2::Ec2Client<CredentialsProviderWrapper, ::hyper::Client>;
+/// Labels identifying the account and the region of an instance, each is present only when
+/// several accounts or regions are configured.
+pub fn owner_label_names(settings: &AwsInstancesPollerSettings) -> Vec<String> {
+    let mut names = Vec::new();
+    if settings.accounts.is_some() {
+        names.push("account".to_owned());
+    }
+    if settings.regions.is_some() {
+        names.push("region".to_owned());
+    }
+    names
+}
+
+fn owner_labels(owner_label_names: &Vec<String>, account: &str, region: Region) -> Vec<(String, String)> {
+    owner_label_names.iter()
+        .map(|n| (n.clone(), if n == "account" { account.to_owned() } else { region.to_string() }))
+        .collect()
+}
 
+/// Polls running instances of every account in every region, shards of each are queried
+/// separately on a bounded pool of workers. Metrics are updated only when all of them succeed.
 pub struct AwsInstancesPoller {
-    credentials_provider: CredentialsProviderWrapper,
-    region: Region,
+    accounts: Vec<AwsAccount>,
+    regions: Vec<Region>,
+    owner_label_names: Vec<String>,
+    shards: Vec<Vec<ec2::Filter>>,
+    max_parallel_requests: usize,
     max_chunk_size: Option<i32>,
     expose_tags: Vec<String>,
-    gauges: GaugeVec
+    cost_group_tags: Vec<String>,
+    instance_types: InstanceTypeCatalogue,
+    spot_prices: SpotPriceTable,
+    inventory: InstanceInventory,
+    snapshot: SnapshotCollector
+}
+
+/// Metrics of running instances built by a single poll.
+struct InstanceGauges {
+    state: GaugeVec,
+    vcpus: GaugeVec,
+    memory: GaugeVec,
+    on_demand_prices: GaugeVec,
+    hourly_costs: GaugeVec,
+    tag_hourly_costs: GaugeVec
+}
+
+impl InstanceGauges {
+    fn new(owner_label_names: &Vec<String>, expose_tags: &Vec<String>) -> Result<InstanceGauges, PrometheusError> {
+        let label_names = instance_label_names(owner_label_names, expose_tags);
+        let tag_cost_label_names: Vec<String> = owner_label_names.iter().cloned()
+            .chain(vec!["tag".to_owned(), "value".to_owned()])
+            .collect();
+        Ok(InstanceGauges {
+            state: Self::new_gauges("AwsInstanceState", "Identifies a running AWS instance", &label_names)?,
+            vcpus: Self::new_gauges("aws_instance_vcpus", "Number of vCPUs of a running AWS instance",
+                                    &label_names)?,
+            memory: Self::new_gauges("aws_instance_memory_bytes", "Memory size of a running AWS instance",
+                                     &label_names)?,
+            on_demand_prices: Self::new_gauges("aws_instance_on_demand_price",
+                                               "Hourly on-demand price of a running AWS instance type",
+                                               &label_names)?,
+            hourly_costs: Self::new_gauges("aws_instance_hourly_cost",
+                                           "Estimated hourly cost of a running AWS instance",
+                                           &label_names)?,
+            tag_hourly_costs: Self::new_gauges("aws_tag_hourly_cost",
+                                               "Estimated hourly cost of running AWS instances by tag value",
+                                               &tag_cost_label_names)?
+        })
+    }
+
+    fn new_gauges(name: &str, help: &str, label_names: &Vec<String>) -> Result<GaugeVec, PrometheusError> {
+        let opts = Opts::new(name, help);
+        let labels: Vec<&str> = label_names.iter().map(|s| &**s).collect();
+        GaugeVec::new(opts, labels.as_slice())
+    }
+
+    fn collector(&self) -> CollectorGroup {
+        CollectorGroup::new(vec![
+            Box::new(self.state.clone()),
+            Box::new(self.vcpus.clone()),
+            Box::new(self.memory.clone()),
+            Box::new(self.on_demand_prices.clone()),
+            Box::new(self.hourly_costs.clone()),
+            Box::new(self.tag_hourly_costs.clone()),
+        ])
+    }
 }
 
 impl AwsInstancesPoller {
-    pub fn new(settings_provider: &AwsInstancesPollerSettingsProvider) -> PollerResult<AwsInstancesPoller> {
+    pub fn new(settings_provider: &AwsInstancesPollerSettingsProvider,
+               http_clients: &HttpClientFactory,
+               instance_types: InstanceTypeCatalogue,
+               spot_prices: SpotPriceTable,
+               inventory: InstanceInventory) -> PollerResult<AwsInstancesPoller> {
         let settings = settings_provider.aws_instances_poller_settings();
+        let regions = instance_regions(&settings)?;
+        let accounts = instance_accounts("instances", &settings, &regions, http_clients)?;
+        let owner_label_names = owner_label_names(&settings);
+        let shards = match settings.shards {
+            Some(shards) => shards.into_iter()
+                .map(|f| vec![ec2::Filter { name: Some(f.name), values: Some(f.values) }])
+                .collect(),
+            None => vec![Vec::new()]
+        };
         let result = AwsInstancesPoller {
-            credentials_provider: CredentialsProviderWrapper::from_type(
-                settings.credentials_provider.unwrap_or(AwsCredentialsProviderType::Default))?,
-            region: Region::from_str(&settings.region)?,
+            accounts: accounts,
+            regions: regions,
+            shards: shards,
+            max_parallel_requests: settings.max_parallel_requests.unwrap_or(DEFAULT_MAX_PARALLEL_REQUESTS),
             max_chunk_size: settings.max_chunk_size,
-            gauges: Self::new_gauges(&settings.expose_tags)?,
+            cost_group_tags: settings.cost_group_tags.unwrap_or(Vec::new()),
+            instance_types: instance_types,
+            spot_prices: spot_prices,
+            inventory: inventory,
+            snapshot: SnapshotCollector::new(
+                "instances", Box::new(InstanceGauges::new(&owner_label_names, &settings.expose_tags)?.collector()),
+                settings.max_staleness)?,
+            owner_label_names: owner_label_names,
             expose_tags: settings.expose_tags,
         };
-        if let Some(e) = result.credentials_provider.test() { Err(e)? }
-            else if let Some(e) = result.test_describe_instances() { Err(e)? }
         Ok(result)
     }
 
-    fn new_gauges(expose_tags: &Vec<String>) -> Result<GaugeVec, PrometheusError> {
-        let opts = Opts::new("AwsInstanceState", "Identifies a running AWS instance");
-        let labels: Vec<&str> = vec!["id", "availability_zone", "platform", "type", "lifecycle", "networking"].into_iter()
-            .chain(expose_tags.iter().map(|s| &**s)).collect();
-        GaugeVec::new(opts, labels.as_slice())
-    }
-
-    fn get_ec2_client(&self) -> Ec2Client {
-        Ec2Client::new(default_tls_client().unwrap(), self.credentials_provider.clone(), self.region)
-    }
-
-    fn test_describe_instances(&self) -> Option<AwsPollerError> {
-        let client = self.get_ec2_client();
+    fn test_describe_instances(&self, account: &AwsAccount, region: Region) -> Option<AwsPollerError> {
+        let client = account.ec2_client(region);
         let mut req: ec2::DescribeInstancesRequest = Default::default();
         req.dry_run = Some(true);
 
@@ -208,73 +433,160 @@ impl AwsInstancesPoller {
     }
 }
 
-fn to_hashmap(labels: &Vec<(String, String)>) -> HashMap<&str, &str> {
+/// Names of labels identifying an instance in `AwsInstanceState` and the derived metrics.
+pub fn instance_label_names(owner_label_names: &Vec<String>, expose_tags: &Vec<String>) -> Vec<String> {
+    owner_label_names.iter().cloned()
+        .chain(vec!["id", "availability_zone", "platform", "type", "lifecycle", "networking"].into_iter()
+            .map(|s| s.to_owned()))
+        .chain(expose_tags.iter().map(|t| tag_label_name(t)))
+        .collect()
+}
+
+/// Converts AWS tag key to a valid Prometheus label name, e.g. `aws:autoscaling:groupName` is
+/// exposed as `aws_autoscaling_groupName`.
+pub fn tag_label_name(tag: &str) -> String {
+    tag.chars().map(|c| if (c.is_ascii() && c.is_alphanumeric()) || c == '_' { c } else { '_' }).collect()
+}
+
+/// Builds labels of the exposed tags from the resource tags, tag keys are matched case-insensitively
+/// and missing tags are exposed as empty labels.
+pub fn tag_labels(expose_tags: &Vec<String>, tags: &Vec<(String, String)>) -> Vec<(String, String)> {
+    expose_tags.iter()
+        .map(|e| match tags.iter().find(|&t| e.eq_ignore_ascii_case(&t.0)) {
+            Some(ft) => (tag_label_name(e), ft.1.clone()),
+            None => (tag_label_name(e), "".to_owned())
+        })
+        .collect()
+}
+
+pub fn to_hashmap(labels: &Vec<(String, String)>) -> HashMap<&str, &str> {
     let literals: Vec<(&str, &str)> = labels.iter().map(|l| -> (&str, &str)
         { (&l.0, &l.1) }).collect();
     literals.iter().cloned().collect()
 }
 
 impl Poller for AwsInstancesPoller {
-    fn poll(&self) {
-        let running_filter = ec2::Filter {
-            name: Some(String::from("instance-state-code")),
-            values: Some(vec![String::from("16")])
-        };
-        let mut current_metrics: Vec<_> = self.gauges.collect().iter().next().unwrap().get_metric().iter()
-            .map(|m| m.get_label().iter()
-                .map(|l| (l.get_name().to_owned(), l.get_value().to_owned())).collect::<HashMap<_, _>>())
-            .collect();
-        let mut query_err = None;
-        {
-            let di = PaginatedIterator::new(
-                DescribeInstancesRequestor::new(self.get_ec2_client(), vec![running_filter], self.max_chunk_size),
-                &mut query_err);
+    fn name(&self) -> &str {
+        "instances"
+    }
 
-            for instance in di {
-                if let Some(tags) = instance.tags {
-                    let id = instance.instance_id.unwrap();
-                    let mut subsidiary_labels = vec![
-                        ("id".to_owned(), id.clone()),
-                        ("availability_zone".to_owned(), instance.placement.unwrap().availability_zone.unwrap()),
-                        ("platform".to_owned(), instance.platform.unwrap_or("linux".to_owned())),
-                        ("type".to_owned(), instance.instance_type.unwrap()),
-                        ("lifecycle".to_owned(), instance.instance_lifecycle.unwrap_or("ondemand".to_owned())),
-                        ("networking".to_owned(), if instance.vpc_id.is_some() { "vpc".to_owned() } else { "classic".to_owned() })
-                    ];
-                    current_metrics.retain(|m| m[&"id".to_owned()] != id);
-                    let mut labels = Vec::with_capacity(subsidiary_labels.len() + self.expose_tags.len());
-                    labels.append(&mut subsidiary_labels);
-                    for e in self.expose_tags.iter() {
-                        let m = match tags.iter().find(|&t| e.eq_ignore_ascii_case(t.key.as_ref().unwrap())) {
-                            Some(ft) => (e.clone(), ft.clone().value.unwrap()),
-                            None => (e.clone(), "".to_owned())
-                        };
-                        labels.push(m);
-                    }
-                    match self.gauges.get_metric_with(&to_hashmap(&labels)) {
-                        Ok(m) => m.set(1.0),
-                        Err(e) => println!("Error {:?} on {:?}", e, labels)
-                    }
+    fn check(&self) -> Option<AwsPollerError> {
+        self.accounts.iter()
+            .filter_map(|a| a.credentials_provider.test().or_else(|| self.regions.iter()
+                .filter_map(|&r| self.test_describe_instances(a, r))
+                .next()))
+            .next()
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let targets: Vec<_> = self.accounts.iter().enumerate()
+            .flat_map(|(i, a)| self.regions.iter()
+                .flat_map(move |r| self.shards.iter().map(move |s| (i, a.clone(), *r, s.clone()))))
+            .collect();
+        let owners: Vec<(usize, Region)> = targets.iter().map(|t| (t.0, t.2)).collect();
+        let max_chunk_size = self.max_chunk_size;
+        let results = fan_out(targets, self.max_parallel_requests, move |(_, account, region, mut filters)| {
+            filters.push(ec2::Filter {
+                name: Some(String::from("instance-state-code")),
+                values: Some(vec![String::from("16")])
+            });
+            let mut query_err = None;
+            let instances: Vec<_> = PaginatedIterator::new(
+                DescribeInstancesRequestor::new(account.ec2_client(region), filters, max_chunk_size),
+                &mut query_err).collect();
+            match query_err {
+                Some(e) => Err(AwsPollerError::from(e)),
+                None => Ok(instances)
+            }
+        });
+        let mut instances = Vec::new();
+        let mut poll_err = None;
+        for ((owner, region), result) in owners.into_iter().zip(results) {
+            match result {
+                Some(Ok(i)) => instances.extend(i.into_iter().map(|i| (owner, region, i))),
+                Some(Err(e)) => {
+                    let _ = writeln!(&mut stderr(), "Unexpected error during instance enumeration: {:?}", e);
+                    self.accounts[owner].credentials_provider.revalidate_on(&e);
+                    poll_err = Some(e);
                 }
+                None => poll_err = Some(AwsPollerError::UnknownError("Instance enumeration failed".to_owned()))
             }
         }
-        if query_err.is_some() {
-            let _ = writeln!(&mut stderr(), "Unexpected error during instance enumeration: {:?}",
-                             query_err);
-        } else {
-            // Delete instances that are not in running state anymore
-            for m in current_metrics.iter() {
-                let labels = m.iter().map(|t| (t.0.as_str(), t.1.as_str())).collect::<HashMap<_, _>>();
-                println!("Deleting {:?}", labels["id"]);
-                if self.gauges.remove(&labels).is_err() {
-                    let _ = writeln!(&mut stderr(), "Instance disappeared?");
+        if let Some(e) = poll_err {
+            self.snapshot.fail();
+            return Err(e);
+        }
+        let gauges = InstanceGauges::new(&self.owner_label_names, &self.expose_tags)?;
+        let mut tag_costs: HashMap<(Vec<(String, String)>, String, String), f64> = HashMap::new();
+        let mut inventory = Vec::new();
+        for (owner, region, instance) in instances {
+            if let Some(tags) = instance.tags {
+                let id = instance.instance_id.unwrap();
+                let platform = instance.platform.unwrap_or("linux".to_owned());
+                let instance_type = instance.instance_type.unwrap();
+                let availability_zone = instance.placement.unwrap().availability_zone.unwrap();
+                let lifecycle = instance.instance_lifecycle.unwrap_or("ondemand".to_owned());
+                let networking = if instance.vpc_id.is_some() { "vpc".to_owned() } else { "classic".to_owned() };
+                let mut subsidiary_labels = vec![
+                    ("id".to_owned(), id.clone()),
+                    ("availability_zone".to_owned(), availability_zone.clone()),
+                    ("platform".to_owned(), platform.clone()),
+                    ("type".to_owned(), instance_type.clone()),
+                    ("lifecycle".to_owned(), lifecycle.clone()),
+                    ("networking".to_owned(), networking.clone())
+                ];
+                let tags: Vec<(String, String)> = tags.into_iter()
+                    .map(|t| (t.key.unwrap_or(String::new()), t.value.unwrap_or(String::new())))
+                    .collect();
+                let account = &self.accounts[owner].name;
+                let owner_labels = owner_labels(&self.owner_label_names, account, region);
+                let mut labels = Vec::with_capacity(
+                    owner_labels.len() + subsidiary_labels.len() + self.expose_tags.len());
+                labels.extend(owner_labels.iter().cloned());
+                labels.append(&mut subsidiary_labels);
+                labels.append(&mut tag_labels(&self.expose_tags, &tags));
+                set_gauge(&gauges.state, &labels, 1.0);
+                inventory.push(InventoryInstance {
+                    account: account.clone(),
+                    region: region,
+                    labels: labels.clone()
+                });
+                let info = self.instance_types.get(&instance_type);
+                if let Some(ref info) = info {
+                    set_gauge(&gauges.vcpus, &labels, info.vcpus as f64);
+                    set_gauge(&gauges.memory, &labels, info.memory_bytes());
+                    if let Some(price) = info.on_demand_price(&platform) {
+                        set_gauge(&gauges.on_demand_prices, &labels, price);
+                    }
+                }
+                let cost = if lifecycle == "spot" {
+                    self.spot_prices.get(&availability_zone, &platform, &networking, &instance_type)
+                } else {
+                    info.and_then(|i| i.on_demand_price(&platform))
+                };
+                if let Some(c) = cost {
+                    set_gauge(&gauges.hourly_costs, &labels, c);
+                    for (tag, value) in self.cost_group_tags.iter().cloned()
+                        .zip(tag_labels(&self.cost_group_tags, &tags).into_iter().map(|l| l.1)) {
+                        *tag_costs.entry((owner_labels.clone(), tag, value)).or_insert(0.0) += c;
+                    }
                 }
             }
         }
+        for ((mut labels, tag, value), cost) in tag_costs {
+            labels.push(("tag".to_owned(), tag));
+            labels.push(("value".to_owned(), value));
+            set_gauge(&gauges.tag_hourly_costs, &labels, cost);
+        }
+        self.snapshot.publish(&gauges.collector());
+        self.inventory.publish(inventory);
+        Ok(())
     }
 
     fn counters(&self) -> Box<Collector> {
-        Box::new(self.gauges.clone())
+        let mut collectors: Vec<Box<Collector>> = vec![Box::new(self.snapshot.clone())];
+        collectors.extend(self.accounts.iter().map(|a| a.credentials_provider.counters()));
+        Box::new(CollectorGroup::new(collectors))
     }
 }
 
@@ -328,38 +640,57 @@ impl DescribeInstancesRequestor {
 pub struct AwsSpotPricesPoller {
     credentials_provider: CredentialsProviderWrapper,
     region: Region,
+    http_client: SharedHttpClient,
     max_chunk_size: Option<i32>,
     availability_zones: Option<Vec<String>>,
     products: Option<Vec<String>>,
     instance_types: Option<Vec<String>>,
-    gauges: GaugeVec
+    instance_type_catalogue: InstanceTypeCatalogue,
+    spot_prices: SpotPriceTable,
+    gauges: GaugeVec,
+    vcpus: GaugeVec,
+    memory: GaugeVec,
+    on_demand_prices: GaugeVec
 }
 
 impl AwsSpotPricesPoller {
-    pub fn new(settings_provider: &AwsSpotPricesPollerSettingsProvider) -> PollerResult<Self> {
+    pub fn new(settings_provider: &AwsSpotPricesPollerSettingsProvider,
+               http_clients: &HttpClientFactory,
+               instance_type_catalogue: InstanceTypeCatalogue,
+               spot_prices: SpotPriceTable) -> PollerResult<Self> {
         let settings = settings_provider.aws_spot_prices_poller_settings();
+        let region = Region::from_str(&settings.region)?;
+        let credentials_provider = CredentialsProviderWrapper::from_type(
+            "spot_prices", settings.credentials_provider, settings.profile_name, settings.credentials_file,
+            region, http_clients)?;
+        let http_client = http_clients.client(settings.endpoint_url.as_ref().map(|s| &**s),
+                                              &credentials_provider, region)?;
         let result = AwsSpotPricesPoller {
-            credentials_provider: CredentialsProviderWrapper::from_type(
-                settings.credentials_provider.unwrap_or(AwsCredentialsProviderType::Default))?,
-            region: Region::from_str(&settings.region)?,
+            credentials_provider: credentials_provider,
+            region: region,
+            http_client: http_client,
             max_chunk_size: settings.max_chunk_size,
             availability_zones: settings.availability_zones,
             products: settings.products,
             instance_types: settings.instance_types,
-            gauges: Self::new_gauges()?,
+            instance_type_catalogue: instance_type_catalogue,
+            spot_prices: spot_prices,
+            gauges: Self::new_gauges("AwsSpotPrices", "Identifies a history of spot prices")?,
+            vcpus: Self::new_gauges("aws_spot_instance_vcpus", "Number of vCPUs of a spot instance type")?,
+            memory: Self::new_gauges("aws_spot_instance_memory_bytes", "Memory size of a spot instance type")?,
+            on_demand_prices: Self::new_gauges("aws_spot_on_demand_price",
+                                               "Hourly on-demand price of a spot instance type")?,
         };
-        if let Some(e) = result.credentials_provider.test() { Err(e)? }
-            else if let Some(e) = result.test_describe_spot_prices() { Err(e)? }
         Ok(result)
     }
 
-    fn new_gauges() -> Result<GaugeVec, PrometheusError> {
-        let opts = Opts::new("AwsSpotPrices", "Identifies a history of spot prices");
+    fn new_gauges(name: &str, help: &str) -> Result<GaugeVec, PrometheusError> {
+        let opts = Opts::new(name, help);
         GaugeVec::new(opts, &["availability_zone", "platform", "type", "networking"])
     }
 
     fn get_ec2_client(&self) -> Ec2Client {
-        Ec2Client::new(default_tls_client().unwrap(), self.credentials_provider.clone(), self.region)
+        Ec2Client::new(self.http_client.clone(), self.credentials_provider.clone(), self.region)
     }
 
     fn test_describe_spot_prices(&self) -> Option<AwsPollerError> {
@@ -400,7 +731,15 @@ impl AwsSpotPricesPoller {
 }
 
 impl Poller for AwsSpotPricesPoller {
-    fn poll(&self) {
+    fn name(&self) -> &str {
+        "spot_prices"
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        self.credentials_provider.test().or_else(|| self.test_describe_spot_prices())
+    }
+
+    fn poll(&self) -> PollerResult<()> {
         let mut query_err = None;
         {
             let mut filters = Vec::with_capacity(3);
@@ -417,22 +756,47 @@ impl Poller for AwsSpotPricesPoller {
                 &mut query_err);
             for sp in spot_prices_iterator {
                 let product = sp.product_description.unwrap_or(String::new());
+                let platform = Self::product_to_platform(&product).unwrap_or("");
+                let networking = Self::product_to_networking(&product).unwrap_or("");
+                let availability_zone = sp.availability_zone.unwrap_or(String::new());
+                let instance_type = sp.instance_type.unwrap_or(String::new());
+                let price = sp.spot_price.unwrap_or(String::new()).parse::<f64>().unwrap_or(0.0);
                 let labels = vec![
-                    ("availability_zone".to_owned(), sp.availability_zone.unwrap_or(String::new())),
-                    ("platform".to_owned(), Self::product_to_platform(&product).unwrap_or("").to_owned()),
-                    ("networking".to_owned(), Self::product_to_networking(&product).unwrap_or("").to_owned()),
-                    ("type".to_owned(), sp.instance_type.unwrap_or(String::new()))
+                    ("availability_zone".to_owned(), availability_zone.clone()),
+                    ("platform".to_owned(), platform.to_owned()),
+                    ("networking".to_owned(), networking.to_owned()),
+                    ("type".to_owned(), instance_type.clone())
                 ];
                 match self.gauges.get_metric_with(&to_hashmap(&labels)) {
-                    Ok(m) => m.set(sp.spot_price.unwrap_or(String::new()).parse::<f64>().unwrap_or(0.0)),
-                    Err(e) => println!("Error {:?} on {:?}", e, labels)
+                    Ok(m) => m.set(price),
+                    Err(e) => { let _ = writeln!(&mut stderr(), "Error {:?} on {:?}", e, labels); }
+                }
+                self.spot_prices.set(&availability_zone, platform, networking, &instance_type, price);
+                if let Some(info) = self.instance_type_catalogue.get(&instance_type) {
+                    set_gauge(&self.vcpus, &labels, info.vcpus as f64);
+                    set_gauge(&self.memory, &labels, info.memory_bytes());
+                    if let Some(price) = info.on_demand_price(platform) {
+                        set_gauge(&self.on_demand_prices, &labels, price);
+                    }
                 }
             }
         }
+        if let Some(e) = query_err.map(AwsPollerError::from) {
+            let _ = writeln!(&mut stderr(), "Unexpected error during spot prices enumeration: {:?}", e);
+            self.credentials_provider.revalidate_on(&e);
+            return Err(e);
+        }
+        Ok(())
     }
 
     fn counters(&self) -> Box<Collector> {
-        Box::new(self.gauges.clone())
+        Box::new(CollectorGroup::new(vec![
+            Box::new(self.gauges.clone()),
+            Box::new(self.vcpus.clone()),
+            Box::new(self.memory.clone()),
+            Box::new(self.on_demand_prices.clone()),
+            self.credentials_provider.counters(),
+        ]))
     }
 }
 
@@ -488,3 +852,807 @@ impl DescribeSpotPricesRequestor {
         }
     }
 }
+
+pub fn current_series(gauges: &GaugeVec) -> Vec<HashMap<String, String>> {
+    gauges.collect().iter()
+        .flat_map(|mf| mf.get_metric().iter()
+            .map(|m| m.get_label().iter()
+                .map(|l| (l.get_name().to_owned(), l.get_value().to_owned())).collect::<HashMap<_, _>>())
+            .collect::<Vec<_>>())
+        .collect()
+}
+
+pub fn remove_stale_series(gauges: &GaugeVec, stale: &Vec<HashMap<String, String>>) {
+    for m in stale.iter() {
+        let labels = m.iter().map(|t| (t.0.as_str(), t.1.as_str())).collect::<HashMap<_, _>>();
+        if gauges.remove(&labels).is_err() {
+            let _ = writeln!(&mut stderr(), "Series disappeared: {:?}", labels);
+        }
+    }
+}
+
+/// Sets the value of the series identified by `labels` and marks it as fresh.
+pub fn set_series(gauges: &GaugeVec, stale: &mut Vec<HashMap<String, String>>,
+                  labels: &Vec<(String, String)>, value: f64) {
+    stale.retain(|m| labels.iter().any(|l| m.get(&l.0) != Some(&l.1)));
+    set_gauge(gauges, labels, value);
+}
+
+pub fn set_gauge(gauges: &GaugeVec, labels: &Vec<(String, String)>, value: f64) {
+    match gauges.get_metric_with(&to_hashmap(labels)) {
+        Ok(m) => m.set(value),
+        Err(e) => { let _ = writeln!(&mut stderr(), "Error {:?} on {:?}", e, labels); }
+    }
+}
+
+pub struct AwsSpotRequestsPoller {
+    credentials_provider: CredentialsProviderWrapper,
+    region: Region,
+    http_client: SharedHttpClient,
+    max_chunk_size: Option<i32>,
+    states: Option<Vec<String>>,
+    instance_request_states: GaugeVec,
+    instance_request_prices: GaugeVec,
+    fleet_request_states: GaugeVec,
+    fleet_request_prices: GaugeVec,
+    fleet_target_capacities: GaugeVec,
+    fleet_fulfilled_capacities: GaugeVec
+}
+
+impl AwsSpotRequestsPoller {
+    /// Returns `None` if the poller is not configured.
+    pub fn new(settings_provider: &AwsSpotRequestsPollerSettingsProvider,
+               http_clients: &HttpClientFactory) -> PollerResult<Option<Self>> {
+        let settings = match settings_provider.aws_spot_requests_poller_settings() {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        let region = Region::from_str(&settings.region)?;
+        let credentials_provider = CredentialsProviderWrapper::from_type(
+            "spot_requests", settings.credentials_provider, settings.profile_name, settings.credentials_file,
+            region, http_clients)?;
+        let http_client = http_clients.client(settings.endpoint_url.as_ref().map(|s| &**s),
+                                              &credentials_provider, region)?;
+        let result = AwsSpotRequestsPoller {
+            credentials_provider: credentials_provider,
+            region: region,
+            http_client: http_client,
+            max_chunk_size: settings.max_chunk_size,
+            states: settings.states,
+            instance_request_states: GaugeVec::new(
+                Opts::new("aws_spot_instance_request_state", "Identifies a state of AWS spot instance request"),
+                &["id", "state", "status_code", "type", "availability_zone", "product", "instance_id"])?,
+            instance_request_prices: GaugeVec::new(
+                Opts::new("aws_spot_instance_request_bid_price", "Maximum price of AWS spot instance request"),
+                &["id"])?,
+            fleet_request_states: GaugeVec::new(
+                Opts::new("aws_spot_fleet_request_state", "Identifies a state of AWS spot fleet request"),
+                &["id", "state", "activity_status"])?,
+            fleet_request_prices: GaugeVec::new(
+                Opts::new("aws_spot_fleet_request_bid_price", "Maximum price per unit of AWS spot fleet request"),
+                &["id"])?,
+            fleet_target_capacities: GaugeVec::new(
+                Opts::new("aws_spot_fleet_request_target_capacity", "Target capacity of AWS spot fleet request"),
+                &["id"])?,
+            fleet_fulfilled_capacities: GaugeVec::new(
+                Opts::new("aws_spot_fleet_request_fulfilled_capacity", "Fulfilled capacity of AWS spot fleet request"),
+                &["id"])?,
+        };
+        Ok(Some(result))
+    }
+
+    fn get_ec2_client(&self) -> Ec2Client {
+        Ec2Client::new(self.http_client.clone(), self.credentials_provider.clone(), self.region)
+    }
+
+    fn test_describe_spot_requests(&self) -> Option<AwsPollerError> {
+        let client = self.get_ec2_client();
+        let mut req: ec2::DescribeSpotInstanceRequestsRequest = Default::default();
+        req.dry_run = Some(true);
+
+        match client.describe_spot_instance_requests(&req) {
+            Err(e) => {
+                match AwsPollerError::from(e) {
+                    AwsPollerError::NoError => None,
+                    e => Some(e)
+                }
+            }
+            _ => None
+        }
+    }
+
+    fn poll_instance_requests(&self) -> PollerResult<()> {
+        let mut req: ec2::DescribeSpotInstanceRequestsRequest = Default::default();
+        if let Some(ref states) = self.states {
+            req.filters = Some(vec![ec2::Filter {
+                name: Some("state".to_owned()),
+                values: Some(states.clone())
+            }]);
+        }
+        let requests = self.get_ec2_client().describe_spot_instance_requests(&req)?
+            .spot_instance_requests.unwrap_or(Vec::new());
+
+        let mut stale_states = current_series(&self.instance_request_states);
+        let mut stale_prices = current_series(&self.instance_request_prices);
+        for r in requests {
+            let id = r.spot_instance_request_id.unwrap_or(String::new());
+            let (instance_type, availability_zone) = match r.launch_specification {
+                Some(spec) => (spec.instance_type.unwrap_or(String::new()),
+                               spec.placement.and_then(|p| p.availability_zone)
+                                   .or(r.launched_availability_zone).unwrap_or(String::new())),
+                None => (String::new(), r.launched_availability_zone.unwrap_or(String::new()))
+            };
+            let labels = vec![
+                ("id".to_owned(), id.clone()),
+                ("state".to_owned(), r.state.unwrap_or(String::new())),
+                ("status_code".to_owned(), r.status.and_then(|s| s.code).unwrap_or(String::new())),
+                ("type".to_owned(), instance_type),
+                ("availability_zone".to_owned(), availability_zone),
+                ("product".to_owned(), r.product_description.unwrap_or(String::new())),
+                ("instance_id".to_owned(), r.instance_id.unwrap_or(String::new()))
+            ];
+            set_series(&self.instance_request_states, &mut stale_states, &labels, 1.0);
+            if let Some(price) = r.spot_price.and_then(|p| p.parse::<f64>().ok()) {
+                set_series(&self.instance_request_prices, &mut stale_prices,
+                           &vec![("id".to_owned(), id)], price);
+            }
+        }
+        remove_stale_series(&self.instance_request_states, &stale_states);
+        remove_stale_series(&self.instance_request_prices, &stale_prices);
+        Ok(())
+    }
+
+    fn poll_fleet_requests(&self) -> PollerResult<()> {
+        let mut stale_states = current_series(&self.fleet_request_states);
+        let mut stale_prices = current_series(&self.fleet_request_prices);
+        let mut stale_targets = current_series(&self.fleet_target_capacities);
+        let mut stale_fulfilled = current_series(&self.fleet_fulfilled_capacities);
+        let mut query_err = None;
+        {
+            let fleet_requests = PaginatedIterator::new(
+                DescribeSpotFleetRequestsRequestor::new(self.get_ec2_client(), self.max_chunk_size),
+                &mut query_err);
+            for r in fleet_requests {
+                let state = r.spot_fleet_request_state.unwrap_or(String::new());
+                if let Some(ref states) = self.states {
+                    if !states.contains(&state) {
+                        continue;
+                    }
+                }
+                let id = vec![("id".to_owned(), r.spot_fleet_request_id.unwrap_or(String::new()))];
+                let mut labels = id.clone();
+                labels.push(("state".to_owned(), state));
+                labels.push(("activity_status".to_owned(), r.activity_status.unwrap_or(String::new())));
+                set_series(&self.fleet_request_states, &mut stale_states, &labels, 1.0);
+                if let Some(config) = r.spot_fleet_request_config {
+                    if let Some(price) = config.spot_price.and_then(|p| p.parse::<f64>().ok()) {
+                        set_series(&self.fleet_request_prices, &mut stale_prices, &id, price);
+                    }
+                    if let Some(target) = config.target_capacity {
+                        set_series(&self.fleet_target_capacities, &mut stale_targets, &id, target as f64);
+                    }
+                    if let Some(fulfilled) = config.fulfilled_capacity {
+                        set_series(&self.fleet_fulfilled_capacities, &mut stale_fulfilled, &id, fulfilled);
+                    }
+                }
+            }
+        }
+        if let Some(e) = query_err {
+            return Err(AwsPollerError::from(e));
+        }
+        remove_stale_series(&self.fleet_request_states, &stale_states);
+        remove_stale_series(&self.fleet_request_prices, &stale_prices);
+        remove_stale_series(&self.fleet_target_capacities, &stale_targets);
+        remove_stale_series(&self.fleet_fulfilled_capacities, &stale_fulfilled);
+        Ok(())
+    }
+}
+
+impl Poller for AwsSpotRequestsPoller {
+    fn name(&self) -> &str {
+        "spot_requests"
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        self.credentials_provider.test().or_else(|| self.test_describe_spot_requests())
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let mut result = Ok(());
+        if let Err(e) = self.poll_instance_requests() {
+            let _ = writeln!(&mut stderr(), "Unexpected error during spot instance requests enumeration: {:?}", e);
+            self.credentials_provider.revalidate_on(&e);
+            result = Err(e);
+        }
+        if let Err(e) = self.poll_fleet_requests() {
+            let _ = writeln!(&mut stderr(), "Unexpected error during spot fleet requests enumeration: {:?}", e);
+            self.credentials_provider.revalidate_on(&e);
+            result = Err(e);
+        }
+        result
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(vec![
+            Box::new(self.instance_request_states.clone()),
+            Box::new(self.instance_request_prices.clone()),
+            Box::new(self.fleet_request_states.clone()),
+            Box::new(self.fleet_request_prices.clone()),
+            Box::new(self.fleet_target_capacities.clone()),
+            Box::new(self.fleet_fulfilled_capacities.clone()),
+            self.credentials_provider.counters(),
+        ]))
+    }
+}
+
+struct DescribeSpotFleetRequestsRequestor {
+    client: Ec2Client,
+    req: ec2::DescribeSpotFleetRequestsRequest,
+    first_chunk: bool
+}
+
+impl PaginatedRequestor for DescribeSpotFleetRequestsRequestor {
+    type Item = ec2::SpotFleetRequestConfig;
+    type Error = ec2::DescribeSpotFleetRequestsError;
+    fn next_page(&mut self) -> Result<Option<Vec<Self::Item>>, Self::Error> {
+        if self.req.next_token.is_none() && !self.first_chunk {
+            return Ok(None);
+        }
+        self.first_chunk = false;
+        match self.client.describe_spot_fleet_requests(&self.req) {
+            Ok(resp) => {
+                self.req.next_token = resp.next_token.clone();
+                Ok(resp.spot_fleet_request_configs)
+            }
+            Err(e) => {
+                Err(e)
+            }
+        }
+    }
+}
+
+impl DescribeSpotFleetRequestsRequestor {
+    fn new(client: Ec2Client, chunk_size: Option<i32>) -> Self {
+        let mut req: ec2::DescribeSpotFleetRequestsRequest = Default::default();
+        req.max_results = chunk_size;
+        DescribeSpotFleetRequestsRequestor {
+            client: client,
+            req: req,
+            first_chunk: true,
+        }
+    }
+}
+
+/// Service Quotas codes of running instances vCPU quotas by lifecycle and instance class.
+const VCPU_QUOTA_CODES: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("ondemand", "standard", "L-1216C47A"),
+    ("ondemand", "f", "L-74FC7D96"),
+    ("ondemand", "g", "L-DB2E81BA"),
+    ("ondemand", "inf", "L-1945791B"),
+    ("ondemand", "p", "L-417A185B"),
+    ("ondemand", "x", "L-7295265B"),
+    ("spot", "standard", "L-34B43A08"),
+    ("spot", "f", "L-88CF9481"),
+    ("spot", "g", "L-3819A6DF"),
+    ("spot", "inf", "L-B5D1601B"),
+    ("spot", "p", "L-7212CCBC"),
+    ("spot", "x", "L-E3A00192"),
+];
+
+/// Returns the class of the instance type which shares a vCPU quota, e.g. `standard` for
+/// `m5.large` or `g` for `vt1.3xlarge`. Families with quotas of their own are not classified.
+fn vcpu_quota_class(instance_type: &str) -> Option<&'static str> {
+    let family: String = instance_type.chars().take_while(|c| c.is_alphabetic()).collect();
+    match family.as_str() {
+        "a" | "c" | "d" | "h" | "i" | "im" | "is" | "m" | "r" | "t" | "z" => Some("standard"),
+        "f" => Some("f"),
+        "g" | "vt" => Some("g"),
+        "inf" => Some("inf"),
+        "p" => Some("p"),
+        "x" => Some("x"),
+        _ => None
+    }
+}
+
+#[derive(Deserialize)]
+struct GetServiceQuotaResponse {
+    #[serde(rename = "Quota")]
+    quota: ServiceQuota
+}
+
+#[derive(Deserialize)]
+struct ServiceQuota {
+    #[serde(rename = "Value")]
+    value: f64
+}
+
+pub struct AwsAccountLimitsPoller {
+    credentials_provider: CredentialsProviderWrapper,
+    region: Region,
+    http_client: SharedHttpClient,
+    quotas_http_client: SharedHttpClient,
+    max_chunk_size: Option<i32>,
+    instance_types: InstanceTypeCatalogue,
+    account_attributes: GaugeVec,
+    vcpu_quotas: GaugeVec,
+    vcpu_usage: GaugeVec,
+    unknown_instances: GaugeVec
+}
+
+impl AwsAccountLimitsPoller {
+    /// Returns `None` if the poller is not configured.
+    pub fn new(settings_provider: &AwsAccountLimitsPollerSettingsProvider,
+               http_clients: &HttpClientFactory,
+               instance_types: InstanceTypeCatalogue) -> PollerResult<Option<Self>> {
+        let settings = match settings_provider.aws_account_limits_poller_settings() {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        let region = Region::from_str(&settings.region)?;
+        let credentials_provider = CredentialsProviderWrapper::from_type(
+            "account_limits", settings.credentials_provider, settings.profile_name, settings.credentials_file,
+            region, http_clients)?;
+        let http_client = http_clients.client(settings.endpoint_url.as_ref().map(|s| &**s),
+                                              &credentials_provider, region)?;
+        // the custom endpoint is an EC2 one, Service Quotas are always queried at the regional endpoint
+        let quotas_http_client = http_clients.client(None, &credentials_provider, region)?;
+        let result = AwsAccountLimitsPoller {
+            credentials_provider: credentials_provider,
+            region: region,
+            http_client: http_client,
+            quotas_http_client: quotas_http_client,
+            max_chunk_size: settings.max_chunk_size,
+            instance_types: instance_types,
+            account_attributes: GaugeVec::new(
+                Opts::new("aws_account_attribute", "Numeric EC2 account attribute"),
+                &["name"])?,
+            vcpu_quotas: GaugeVec::new(
+                Opts::new("aws_vcpu_quota", "Number of vCPUs allowed to run in the region"),
+                &["lifecycle", "class"])?,
+            vcpu_usage: GaugeVec::new(
+                Opts::new("aws_vcpu_usage", "Number of vCPUs of running instances in the region"),
+                &["lifecycle", "class"])?,
+            unknown_instances: GaugeVec::new(
+                Opts::new("aws_vcpu_usage_unknown_instances",
+                          "Number of running instances whose vCPUs are not accounted in the usage"),
+                &["lifecycle"])?,
+        };
+        Ok(Some(result))
+    }
+
+    fn get_ec2_client(&self) -> Ec2Client {
+        Ec2Client::new(self.http_client.clone(), self.credentials_provider.clone(), self.region)
+    }
+
+    fn test_describe_account_attributes(&self) -> Option<AwsPollerError> {
+        let client = self.get_ec2_client();
+        let mut req: ec2::DescribeAccountAttributesRequest = Default::default();
+        req.dry_run = Some(true);
+
+        match client.describe_account_attributes(&req) {
+            Err(e) => {
+                match AwsPollerError::from(e) {
+                    AwsPollerError::NoError => None,
+                    e => Some(e)
+                }
+            }
+            _ => None
+        }
+    }
+
+    fn poll_account_attributes(&self) -> PollerResult<()> {
+        let req: ec2::DescribeAccountAttributesRequest = Default::default();
+        let attributes = self.get_ec2_client().describe_account_attributes(&req)?
+            .account_attributes.unwrap_or(Vec::new());
+        for a in attributes {
+            let name = a.attribute_name.unwrap_or(String::new());
+            // non-numeric attributes like supported-platforms are skipped
+            let value = a.attribute_values.unwrap_or(Vec::new()).into_iter()
+                .filter_map(|v| v.attribute_value)
+                .filter_map(|v| v.parse::<f64>().ok())
+                .next();
+            if let Some(v) = value {
+                set_gauge(&self.account_attributes, &vec![("name".to_owned(), name)], v);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_vcpu_usage(&self) -> PollerResult<()> {
+        let running_filter = ec2::Filter {
+            name: Some(String::from("instance-state-code")),
+            values: Some(vec![String::from("16")])
+        };
+        let mut usage: HashMap<(&str, &str), u32> = HashMap::new();
+        let mut unknown: HashMap<&str, u32> = HashMap::new();
+        let mut query_err = None;
+        {
+            let di = PaginatedIterator::new(
+                DescribeInstancesRequestor::new(self.get_ec2_client(), vec![running_filter], self.max_chunk_size),
+                &mut query_err);
+            for instance in di {
+                let instance_type = instance.instance_type.unwrap_or(String::new());
+                let lifecycle = match instance.instance_lifecycle {
+                    Some(ref l) if l == "spot" => "spot",
+                    _ => "ondemand"
+                };
+                match (self.instance_types.get(&instance_type), vcpu_quota_class(&instance_type)) {
+                    (Some(info), Some(class)) => *usage.entry((lifecycle, class)).or_insert(0) += info.vcpus,
+                    _ => *unknown.entry(lifecycle).or_insert(0) += 1
+                }
+            }
+        }
+        if let Some(e) = query_err {
+            return Err(AwsPollerError::from(e));
+        }
+        for &(lifecycle, class, _) in VCPU_QUOTA_CODES {
+            let vcpus = usage.get(&(lifecycle, class)).cloned().unwrap_or(0);
+            set_gauge(&self.vcpu_usage, &vec![("lifecycle".to_owned(), lifecycle.to_owned()),
+                                              ("class".to_owned(), class.to_owned())], vcpus as f64);
+        }
+        for lifecycle in ["ondemand", "spot"].iter() {
+            let count = unknown.get(*lifecycle).cloned().unwrap_or(0);
+            set_gauge(&self.unknown_instances, &vec![("lifecycle".to_owned(), lifecycle.to_string())], count as f64);
+        }
+        Ok(())
+    }
+
+    /// Returns `None` if the quota is not available in the region.
+    fn get_vcpu_quota(&self, quota_code: &str) -> PollerResult<Option<f64>> {
+        let mut request = SignedRequest::new("POST", "servicequotas", self.region, "/");
+        request.set_content_type("application/x-amz-json-1.1".to_owned());
+        request.add_header("x-amz-target", "ServiceQuotasV20190624.GetServiceQuota");
+        request.set_payload(Some(format!(r#"{{"ServiceCode":"ec2","QuotaCode":"{}"}}"#, quota_code).into_bytes()));
+        match aws_request::send_signed(&self.quotas_http_client, &self.credentials_provider, request)? {
+            Ok(body) => serde_json::from_str::<GetServiceQuotaResponse>(&body)
+                .map(|r| Some(r.quota.value))
+                .map_err(|e| AwsPollerError::UnknownError(format!("Invalid GetServiceQuota response: {}", e))),
+            Err(ref e) if e.code == "NoSuchResourceException" => Ok(None),
+            Err(e) => Err(e.into_poller_error("GetServiceQuota"))
+        }
+    }
+
+    fn poll_vcpu_quotas(&self) -> PollerResult<()> {
+        for &(lifecycle, class, quota_code) in VCPU_QUOTA_CODES {
+            let labels = vec![("lifecycle".to_owned(), lifecycle.to_owned()), ("class".to_owned(), class.to_owned())];
+            match self.get_vcpu_quota(quota_code)? {
+                Some(quota) => set_gauge(&self.vcpu_quotas, &labels, quota),
+                None => { let _ = self.vcpu_quotas.remove(&to_hashmap(&labels)); }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Poller for AwsAccountLimitsPoller {
+    fn name(&self) -> &str {
+        "account_limits"
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        self.credentials_provider.test().or_else(|| self.test_describe_account_attributes())
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let mut result = Ok(());
+        if let Err(e) = self.poll_vcpu_quotas() {
+            let _ = writeln!(&mut stderr(), "Unexpected error during vCPU quotas query: {:?}", e);
+            self.credentials_provider.revalidate_on(&e);
+            result = Err(e);
+        }
+        if let Err(e) = self.poll_account_attributes() {
+            let _ = writeln!(&mut stderr(), "Unexpected error during account attributes query: {:?}", e);
+            self.credentials_provider.revalidate_on(&e);
+            result = Err(e);
+        }
+        if let Err(e) = self.poll_vcpu_usage() {
+            let _ = writeln!(&mut stderr(), "Unexpected error during vCPU usage calculation: {:?}", e);
+            self.credentials_provider.revalidate_on(&e);
+            result = Err(e);
+        }
+        result
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(vec![
+            Box::new(self.account_attributes.clone()),
+            Box::new(self.vcpu_quotas.clone()),
+            Box::new(self.vcpu_usage.clone()),
+            Box::new(self.unknown_instances.clone()),
+            self.credentials_provider.counters(),
+        ]))
+    }
+}
+
+/// Converts RFC 3339 timestamp returned by AWS API, e.g. `2017-05-01T12:00:00.000Z`, to seconds
+/// since epoch. Timestamps without time zone are taken as UTC.
+fn parse_aws_timestamp(timestamp: &str) -> Option<f64> {
+    let seconds = |t: i64, nanos: u32| t as f64 + nanos as f64 * 1e-9;
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(t) => Some(seconds(t.timestamp(), t.nanosecond())),
+        Err(_) => NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f").ok()
+            .map(|t| seconds(t.timestamp(), t.nanosecond()))
+    }
+}
+
+pub struct AwsInstanceStatusPoller {
+    credentials_provider: CredentialsProviderWrapper,
+    region: Region,
+    http_client: SharedHttpClient,
+    max_chunk_size: Option<i32>,
+    include_all_instances: bool,
+    status_checks: GaugeVec,
+    scheduled_events: GaugeVec
+}
+
+impl AwsInstanceStatusPoller {
+    /// Returns `None` if the poller is not configured.
+    pub fn new(settings_provider: &AwsInstanceStatusPollerSettingsProvider,
+               http_clients: &HttpClientFactory) -> PollerResult<Option<Self>> {
+        let settings = match settings_provider.aws_instance_status_poller_settings() {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        let region = Region::from_str(&settings.region)?;
+        let credentials_provider = CredentialsProviderWrapper::from_type(
+            "instance_status", settings.credentials_provider, settings.profile_name, settings.credentials_file,
+            region, http_clients)?;
+        let http_client = http_clients.client(settings.endpoint_url.as_ref().map(|s| &**s),
+                                              &credentials_provider, region)?;
+        let result = AwsInstanceStatusPoller {
+            credentials_provider: credentials_provider,
+            region: region,
+            http_client: http_client,
+            max_chunk_size: settings.max_chunk_size,
+            include_all_instances: settings.include_all_instances.unwrap_or(false),
+            status_checks: GaugeVec::new(
+                Opts::new("aws_instance_status_check", "Identifies a result of AWS instance status check"),
+                &["id", "availability_zone", "check", "status"])?,
+            scheduled_events: GaugeVec::new(
+                Opts::new("aws_instance_scheduled_event",
+                          "Earliest start time of AWS instance scheduled event in seconds since epoch, \
+                           0 if the time is unknown"),
+                &["id", "availability_zone", "code", "description"])?,
+        };
+        Ok(Some(result))
+    }
+
+    fn get_ec2_client(&self) -> Ec2Client {
+        Ec2Client::new(self.http_client.clone(), self.credentials_provider.clone(), self.region)
+    }
+
+    fn test_describe_instance_status(&self) -> Option<AwsPollerError> {
+        let client = self.get_ec2_client();
+        let mut req: ec2::DescribeInstanceStatusRequest = Default::default();
+        req.dry_run = Some(true);
+
+        match client.describe_instance_status(&req) {
+            Err(e) => {
+                match AwsPollerError::from(e) {
+                    AwsPollerError::NoError => None,
+                    e => Some(e)
+                }
+            }
+            _ => None
+        }
+    }
+}
+
+impl Poller for AwsInstanceStatusPoller {
+    fn name(&self) -> &str {
+        "instance_status"
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        self.credentials_provider.test().or_else(|| self.test_describe_instance_status())
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let mut stale_checks = current_series(&self.status_checks);
+        let mut stale_events = current_series(&self.scheduled_events);
+        let mut query_err = None;
+        {
+            let statuses = PaginatedIterator::new(
+                DescribeInstanceStatusRequestor::new(self.get_ec2_client(), self.include_all_instances,
+                                                     self.max_chunk_size),
+                &mut query_err);
+            for st in statuses {
+                let id = st.instance_id.unwrap_or(String::new());
+                let availability_zone = st.availability_zone.unwrap_or(String::new());
+                let checks = vec![("system", st.system_status), ("instance", st.instance_status)];
+                for (check, summary) in checks {
+                    let status = summary.and_then(|s| s.status).unwrap_or(String::new());
+                    let labels = vec![
+                        ("id".to_owned(), id.clone()),
+                        ("availability_zone".to_owned(), availability_zone.clone()),
+                        ("check".to_owned(), check.to_owned()),
+                        ("status".to_owned(), status)
+                    ];
+                    set_series(&self.status_checks, &mut stale_checks, &labels, 1.0);
+                }
+                for event in st.events.unwrap_or(Vec::new()) {
+                    let description = event.description.unwrap_or(String::new());
+                    // completed and canceled events are kept in the API response for a while
+                    if description.starts_with("[Completed]") || description.starts_with("[Canceled]") {
+                        continue;
+                    }
+                    // events are exported even if their time is unknown, so that none is missed
+                    let not_before = match event.not_before.as_ref().and_then(|t| parse_aws_timestamp(t)) {
+                        Some(t) => t,
+                        None => {
+                            let _ = writeln!(&mut stderr(), "Unknown start time {:?} of {} event of {}",
+                                             event.not_before, description, id);
+                            0.0
+                        }
+                    };
+                    let labels = vec![
+                        ("id".to_owned(), id.clone()),
+                        ("availability_zone".to_owned(), availability_zone.clone()),
+                        ("code".to_owned(), event.code.unwrap_or(String::new())),
+                        ("description".to_owned(), description)
+                    ];
+                    set_series(&self.scheduled_events, &mut stale_events, &labels, not_before);
+                }
+            }
+        }
+        if let Some(e) = query_err.map(AwsPollerError::from) {
+            let _ = writeln!(&mut stderr(), "Unexpected error during instance status enumeration: {:?}", e);
+            self.credentials_provider.revalidate_on(&e);
+            return Err(e);
+        }
+        remove_stale_series(&self.status_checks, &stale_checks);
+        remove_stale_series(&self.scheduled_events, &stale_events);
+        Ok(())
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(vec![
+            Box::new(self.status_checks.clone()),
+            Box::new(self.scheduled_events.clone()),
+            self.credentials_provider.counters(),
+        ]))
+    }
+}
+
+struct DescribeInstanceStatusRequestor {
+    client: Ec2Client,
+    req: ec2::DescribeInstanceStatusRequest,
+    first_chunk: bool
+}
+
+impl PaginatedRequestor for DescribeInstanceStatusRequestor {
+    type Item = ec2::InstanceStatus;
+    type Error = ec2::DescribeInstanceStatusError;
+    fn next_page(&mut self) -> Result<Option<Vec<Self::Item>>, Self::Error> {
+        if self.req.next_token.is_none() && !self.first_chunk {
+            return Ok(None);
+        }
+        self.first_chunk = false;
+        match self.client.describe_instance_status(&self.req) {
+            Ok(resp) => {
+                self.req.next_token = resp.next_token.clone();
+                Ok(resp.instance_statuses)
+            }
+            Err(e) => {
+                Err(e)
+            }
+        }
+    }
+}
+
+impl DescribeInstanceStatusRequestor {
+    fn new(client: Ec2Client, include_all_instances: bool, chunk_size: Option<i32>) -> Self {
+        let mut req: ec2::DescribeInstanceStatusRequest = Default::default();
+        req.include_all_instances = Some(include_all_instances);
+        req.max_results = chunk_size;
+        DescribeInstanceStatusRequestor {
+            client: client,
+            req: req,
+            first_chunk: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Opts, GaugeVec};
+
+    fn request_gauges() -> GaugeVec {
+        GaugeVec::new(Opts::new("test_requests", "Test requests"), &["id", "state"]).unwrap()
+    }
+
+    fn labels(id: &str, state: &str) -> Vec<(String, String)> {
+        vec![("id".to_owned(), id.to_owned()), ("state".to_owned(), state.to_owned())]
+    }
+
+    #[test]
+    fn current_series_lists_labels_of_every_series() {
+        let gauges = request_gauges();
+        assert!(current_series(&gauges).is_empty());
+        set_series(&gauges, &mut Vec::new(), &labels("sir-1", "open"), 1.0);
+        set_series(&gauges, &mut Vec::new(), &labels("sir-2", "active"), 1.0);
+        let mut series: Vec<(String, String)> = current_series(&gauges).into_iter()
+            .map(|s| (s["id"].clone(), s["state"].clone()))
+            .collect();
+        series.sort();
+        assert_eq!(series, vec![("sir-1".to_owned(), "open".to_owned()), ("sir-2".to_owned(), "active".to_owned())]);
+    }
+
+    #[test]
+    fn set_series_keeps_fresh_series_and_removes_the_rest() {
+        let gauges = request_gauges();
+        set_series(&gauges, &mut Vec::new(), &labels("sir-1", "open"), 1.0);
+        set_series(&gauges, &mut Vec::new(), &labels("sir-2", "open"), 1.0);
+        let mut stale = current_series(&gauges);
+        set_series(&gauges, &mut stale, &labels("sir-1", "open"), 2.0);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0]["id"], "sir-2");
+        remove_stale_series(&gauges, &stale);
+        assert_eq!(current_series(&gauges).len(), 1);
+        assert_eq!(gauges.with_label_values(&["sir-1", "open"]).get(), 2.0);
+    }
+
+    #[test]
+    fn series_with_changed_label_replaces_the_previous_one() {
+        let gauges = request_gauges();
+        set_series(&gauges, &mut Vec::new(), &labels("sir-1", "open"), 1.0);
+        let mut stale = current_series(&gauges);
+        set_series(&gauges, &mut stale, &labels("sir-1", "active"), 1.0);
+        remove_stale_series(&gauges, &stale);
+        let series = current_series(&gauges);
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0]["state"], "active");
+    }
+
+    #[test]
+    fn classifies_instance_types_by_vcpu_quota() {
+        assert_eq!(vcpu_quota_class("m5.large"), Some("standard"));
+        assert_eq!(vcpu_quota_class("im4gn.xlarge"), Some("standard"));
+        assert_eq!(vcpu_quota_class("g4dn.xlarge"), Some("g"));
+        assert_eq!(vcpu_quota_class("vt1.3xlarge"), Some("g"));
+        assert_eq!(vcpu_quota_class("inf1.xlarge"), Some("inf"));
+        assert_eq!(vcpu_quota_class("x1e.xlarge"), Some("x"));
+        assert_eq!(vcpu_quota_class("mac1.metal"), None);
+        assert_eq!(vcpu_quota_class(""), None);
+    }
+
+    #[test]
+    fn parses_aws_timestamps() {
+        assert_eq!(parse_aws_timestamp("2017-05-01T12:00:00.000Z"), Some(1493640000.0));
+        assert_eq!(parse_aws_timestamp("2017-05-01T14:00:00.500+02:00"), Some(1493640000.5));
+        assert_eq!(parse_aws_timestamp("2017-05-01T12:00:00"), Some(1493640000.0));
+        assert_eq!(parse_aws_timestamp("not a timestamp"), None);
+    }
+
+    #[test]
+    fn labels_account_and_region_only_when_configured() {
+        let names = vec!["account".to_owned(), "region".to_owned()];
+        assert_eq!(owner_labels(&names, "production", Region::EuWest1),
+                   vec![("account".to_owned(), "production".to_owned()),
+                        ("region".to_owned(), "eu-west-1".to_owned())]);
+        assert_eq!(owner_labels(&vec!["region".to_owned()], "default", Region::UsEast1),
+                   vec![("region".to_owned(), "us-east-1".to_owned())]);
+        assert!(owner_labels(&Vec::new(), "default", Region::UsEast1).is_empty());
+        assert_eq!(instance_label_names(&vec!["region".to_owned()], &vec!["aws:cloudformation:stack-name".to_owned()]),
+                   vec!["region", "id", "availability_zone", "platform", "type", "lifecycle", "networking",
+                        "aws_cloudformation_stack_name"]);
+    }
+
+    #[test]
+    fn converts_tag_keys_to_label_names() {
+        assert_eq!(tag_label_name("aws:autoscaling:groupName"), "aws_autoscaling_groupName");
+        assert_eq!(tag_label_name("kubernetes.io/cluster"), "kubernetes_io_cluster");
+        assert_eq!(tag_label_name("Name"), "Name");
+    }
+
+    #[test]
+    fn exposes_tags_case_insensitively_and_missing_as_empty() {
+        let expose_tags = vec!["Name".to_owned(), "aws:cloudformation:stack-name".to_owned()];
+        let tags = vec![("name".to_owned(), "db-1".to_owned()), ("team".to_owned(), "data".to_owned())];
+        assert_eq!(tag_labels(&expose_tags, &tags), vec![
+            ("Name".to_owned(), "db-1".to_owned()),
+            ("aws_cloudformation_stack_name".to_owned(), "".to_owned()),
+        ]);
+    }
+}
@@ -0,0 +1,193 @@
+use config::AwsRdsPollerSettingsProvider;
+use std::str::FromStr;
+use std::io::{stderr, Write};
+use rusoto::Region;
+use rusoto::rds;
+use prometheus::{Opts, GaugeVec, Collector};
+use prometheus::Error as PrometheusError;
+use pagination::{PaginatedIterator, PaginatedRequestor};
+use poller::{Poller, CollectorGroup};
+use aws_poller::{AwsPollerError, PollerResult, current_series, set_series,
+                 remove_stale_series, tag_labels, tag_label_name};
+use credentials::CredentialsProviderWrapper;
+use aws_connector::{HttpClientFactory, SharedHttpClient};
+
+impl_from_aws_error!(rds, DescribeDBInstancesError, "DescribeDBInstances");
+impl_from_aws_error!(rds, ListTagsForResourceError, "ListTagsForResource");
+
+type RdsClient = rds::RdsClient<CredentialsProviderWrapper, SharedHttpClient>;
+
+pub struct AwsRdsPoller {
+    credentials_provider: CredentialsProviderWrapper,
+    region: Region,
+    http_client: SharedHttpClient,
+    max_chunk_size: Option<i64>,
+    expose_tags: Vec<String>,
+    gauges: GaugeVec,
+    allocated_storage: GaugeVec
+}
+
+impl AwsRdsPoller {
+    /// Returns `None` if the poller is not configured.
+    pub fn new(settings_provider: &AwsRdsPollerSettingsProvider,
+               http_clients: &HttpClientFactory) -> PollerResult<Option<Self>> {
+        let settings = match settings_provider.aws_rds_poller_settings() {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        let expose_tags = settings.expose_tags.unwrap_or(Vec::new());
+        let region = Region::from_str(&settings.region)?;
+        let credentials_provider = CredentialsProviderWrapper::from_type(
+            "rds", settings.credentials_provider, settings.profile_name, settings.credentials_file,
+            region, http_clients)?;
+        let http_client = http_clients.client(settings.endpoint_url.as_ref().map(|s| &**s),
+                                              &credentials_provider, region)?;
+        let result = AwsRdsPoller {
+            credentials_provider: credentials_provider,
+            region: region,
+            http_client: http_client,
+            max_chunk_size: settings.max_chunk_size,
+            gauges: Self::new_gauges(&expose_tags)?,
+            allocated_storage: GaugeVec::new(
+                Opts::new("aws_rds_allocated_storage_bytes", "Allocated storage size of an AWS RDS instance"),
+                &["id"])?,
+            expose_tags: expose_tags,
+        };
+        Ok(Some(result))
+    }
+
+    fn new_gauges(expose_tags: &Vec<String>) -> Result<GaugeVec, PrometheusError> {
+        let opts = Opts::new("aws_rds_instance_state", "Identifies an AWS RDS instance");
+        let tag_label_names: Vec<String> = expose_tags.iter().map(|t| tag_label_name(t)).collect();
+        let labels: Vec<&str> = vec!["id", "class", "engine", "engine_version", "availability_zone", "multi_az",
+                                     "status", "storage_type"].into_iter()
+            .chain(tag_label_names.iter().map(|s| &**s)).collect();
+        GaugeVec::new(opts, labels.as_slice())
+    }
+
+    fn get_rds_client(&self) -> RdsClient {
+        RdsClient::new(self.http_client.clone(), self.credentials_provider.clone(), self.region)
+    }
+
+    /// RDS API does not support dry run, so the smallest allowed page of 20 instances is requested instead.
+    fn test_describe_db_instances(&self) -> Option<AwsPollerError> {
+        let client = self.get_rds_client();
+        let mut req: rds::DescribeDBInstancesMessage = Default::default();
+        req.max_records = Some(20);
+        client.describe_db_instances(&req).err().map(AwsPollerError::from)
+    }
+
+    fn get_tags(&self, client: &RdsClient, arn: &str) -> PollerResult<Vec<(String, String)>> {
+        let req = rds::ListTagsForResourceMessage {
+            resource_name: arn.to_owned(),
+            ..Default::default()
+        };
+        Ok(client.list_tags_for_resource(&req)?.tag_list.unwrap_or(Vec::new()).into_iter()
+            .map(|t| (t.key.unwrap_or(String::new()), t.value.unwrap_or(String::new())))
+            .collect())
+    }
+}
+
+impl Poller for AwsRdsPoller {
+    fn name(&self) -> &str {
+        "rds"
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        self.credentials_provider.test().or_else(|| self.test_describe_db_instances())
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let client = self.get_rds_client();
+        let mut stale = current_series(&self.gauges);
+        let mut stale_storage = current_series(&self.allocated_storage);
+        let mut query_err = None;
+        let mut tags_err = None;
+        {
+            let db_instances = PaginatedIterator::new(
+                DescribeDBInstancesRequestor::new(&client, self.max_chunk_size), &mut query_err);
+            for db in db_instances {
+                let id = db.db_instance_identifier.unwrap_or(String::new());
+                // tags are not returned by DescribeDBInstances
+                let tags = if self.expose_tags.is_empty() {
+                    Vec::new()
+                } else {
+                    match self.get_tags(&client, db.db_instance_arn.as_ref().map(|s| &**s).unwrap_or("")) {
+                        Ok(t) => t,
+                        Err(e) => { tags_err = Some(e); continue; }
+                    }
+                };
+                let mut labels = vec![
+                    ("id".to_owned(), id.clone()),
+                    ("class".to_owned(), db.db_instance_class.unwrap_or(String::new())),
+                    ("engine".to_owned(), db.engine.unwrap_or(String::new())),
+                    ("engine_version".to_owned(), db.engine_version.unwrap_or(String::new())),
+                    ("availability_zone".to_owned(), db.availability_zone.unwrap_or(String::new())),
+                    ("multi_az".to_owned(), db.multi_az.unwrap_or(false).to_string()),
+                    ("status".to_owned(), db.db_instance_status.unwrap_or(String::new())),
+                    ("storage_type".to_owned(), db.storage_type.unwrap_or(String::new()))
+                ];
+                labels.append(&mut tag_labels(&self.expose_tags, &tags));
+                set_series(&self.gauges, &mut stale, &labels, 1.0);
+                if let Some(storage) = db.allocated_storage {
+                    set_series(&self.allocated_storage, &mut stale_storage, &vec![("id".to_owned(), id)],
+                               storage as f64 * 1024.0 * 1024.0 * 1024.0);
+                }
+            }
+        }
+        if let Some(e) = query_err.map(AwsPollerError::from).or(tags_err) {
+            let _ = writeln!(&mut stderr(), "Unexpected error during RDS instances enumeration: {:?}", e);
+            self.credentials_provider.revalidate_on(&e);
+            return Err(e);
+        }
+        remove_stale_series(&self.gauges, &stale);
+        remove_stale_series(&self.allocated_storage, &stale_storage);
+        Ok(())
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(vec![
+            Box::new(self.gauges.clone()),
+            Box::new(self.allocated_storage.clone()),
+            self.credentials_provider.counters(),
+        ]))
+    }
+}
+
+struct DescribeDBInstancesRequestor<'a> {
+    client: &'a RdsClient,
+    req: rds::DescribeDBInstancesMessage,
+    first_chunk: bool
+}
+
+impl<'a> PaginatedRequestor for DescribeDBInstancesRequestor<'a> {
+    type Item = rds::DBInstance;
+    type Error = rds::DescribeDBInstancesError;
+    fn next_page(&mut self) -> Result<Option<Vec<Self::Item>>, Self::Error> {
+        if self.req.marker.is_none() && !self.first_chunk {
+            return Ok(None);
+        }
+        self.first_chunk = false;
+        match self.client.describe_db_instances(&self.req) {
+            Ok(resp) => {
+                self.req.marker = resp.marker.clone();
+                Ok(resp.db_instances)
+            }
+            Err(e) => {
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'a> DescribeDBInstancesRequestor<'a> {
+    fn new(client: &'a RdsClient, chunk_size: Option<i64>) -> Self {
+        let mut req: rds::DescribeDBInstancesMessage = Default::default();
+        req.max_records = chunk_size;
+        DescribeDBInstancesRequestor {
+            client: client,
+            req: req,
+            first_chunk: true,
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::result::Result as StdResult;
+use rusoto::{DispatchSignedRequest, ProvideAwsCredentials, SignedRequest};
+use serde_json;
+use aws_poller::{AwsPollerError, PollerResult};
+use aws_connector::SharedHttpClient;
+use credentials::CredentialsProviderWrapper;
+
+/// Error returned by AWS API in the body of a failed response.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ApiError {
+    pub code: String,
+    pub message: String
+}
+
+#[derive(Deserialize)]
+struct JsonError {
+    #[serde(rename = "__type")]
+    error_type: String,
+    message: Option<String>,
+    #[serde(rename = "Message")]
+    message_capitalized: Option<String>
+}
+
+impl ApiError {
+    /// Parses both JSON protocol errors and query protocol XML errors.
+    fn parse(body: &str) -> ApiError {
+        if let Ok(e) = serde_json::from_str::<JsonError>(body) {
+            return ApiError {
+                // the type may be prefixed with the service namespace, e.g. `com.amazon...#Code`
+                code: e.error_type.rsplit('#').next().unwrap_or("").to_owned(),
+                message: e.message.or(e.message_capitalized).unwrap_or(String::new())
+            };
+        }
+        ApiError {
+            code: xml_element_text(body, "Code").unwrap_or("").to_owned(),
+            message: xml_element_text(body, "Message").unwrap_or(body).to_owned()
+        }
+    }
+
+    pub fn into_poller_error(self, operation: &str) -> AwsPollerError {
+        match self.code.as_str() {
+            "DryRunOperation" => AwsPollerError::NoError,
+            "UnauthorizedOperation" | "AccessDenied" | "AccessDeniedException" =>
+                AwsPollerError::InsufficientPermissions(String::from(operation)),
+            "AuthFailure" | "InvalidClientTokenId" | "SignatureDoesNotMatch" | "UnrecognizedClientException"
+            | "ExpiredToken" | "ExpiredTokenException" => AwsPollerError::InvalidCredentials(self.message),
+            _ => AwsPollerError::UnknownError(format!("{} failed with {}: {}", operation, self.code, self.message))
+        }
+    }
+}
+
+/// Sends a request of an API which is not covered by rusoto. The outer error is a failure to
+/// get a response, the inner one is the error reported by AWS.
+pub fn send(client: &SharedHttpClient, request: &SignedRequest) -> PollerResult<StdResult<String, ApiError>> {
+    let response = client.dispatch(request)?;
+    if response.status.is_success() {
+        Ok(Ok(response.body))
+    } else {
+        Ok(Err(ApiError::parse(&response.body)))
+    }
+}
+
+/// Same as `send` for requests which have to be signed.
+pub fn send_signed(client: &SharedHttpClient, credentials_provider: &CredentialsProviderWrapper,
+                   mut request: SignedRequest) -> PollerResult<StdResult<String, ApiError>> {
+    request.sign(&credentials_provider.credentials()?);
+    send(client, &request)
+}
+
+/// Returns the text of the first `<name>` element of the XML document.
+pub fn xml_element_text<'a>(document: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    document.find(&open)
+        .map(|start| &document[start + open.len()..])
+        .and_then(|rest| rest.find(&close).map(|end| rest[..end].trim()))
+}
+
+/// Returns the contents of the direct `<item>` children of the first `<name>` element, which is
+/// how EC2 returns lists.
+pub fn xml_list_items<'a>(document: &'a str, name: &str) -> Vec<&'a str> {
+    xml_list(document, name, "item")
+}
+
+/// Same as `xml_list_items` for `<member>` children, which other query APIs use for lists.
+pub fn xml_list_members<'a>(document: &'a str, name: &str) -> Vec<&'a str> {
+    xml_list(document, name, "member")
+}
+
+fn xml_list<'a>(document: &'a str, name: &str, item: &str) -> Vec<&'a str> {
+    let mut items = Vec::new();
+    let list = match xml_element_text(document, name) {
+        Some(l) => l,
+        None => return items
+    };
+    let (open, close) = (format!("<{}>", item), format!("</{}>", item));
+    let (mut depth, mut start, mut pos) = (0, 0, 0);
+    while let Some(offset) = list[pos..].find('<') {
+        let tag = pos + offset;
+        if list[tag..].starts_with(&open) {
+            if depth == 0 {
+                start = tag + open.len();
+            }
+            depth += 1;
+        } else if list[tag..].starts_with(&close) && depth > 0 {
+            depth -= 1;
+            if depth == 0 {
+                items.push(&list[start..tag]);
+            }
+        }
+        pos = tag + 1;
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_json_error() {
+        let e = ApiError::parse(r#"{"__type":"com.amazonaws#AccessDeniedException","message":"Not allowed"}"#);
+        assert_eq!(e, ApiError { code: "AccessDeniedException".to_owned(), message: "Not allowed".to_owned() });
+        let e = ApiError::parse(r#"{"__type":"NoSuchResourceException","Message":"No quota"}"#);
+        assert_eq!(e, ApiError { code: "NoSuchResourceException".to_owned(), message: "No quota".to_owned() });
+    }
+
+    #[test]
+    fn parses_xml_error() {
+        let e = ApiError::parse("<Response><Errors><Error><Code>AuthFailure</Code>\
+                                 <Message>Bad keys</Message></Error></Errors></Response>");
+        assert_eq!(e, ApiError { code: "AuthFailure".to_owned(), message: "Bad keys".to_owned() });
+    }
+
+    #[test]
+    fn classifies_error_codes() {
+        let error = |code: &str| ApiError { code: code.to_owned(), message: "m".to_owned() };
+        assert_eq!(error("DryRunOperation").into_poller_error("Op"), AwsPollerError::NoError);
+        assert_eq!(error("AccessDeniedException").into_poller_error("Op"),
+                   AwsPollerError::InsufficientPermissions("Op".to_owned()));
+        assert_eq!(error("ExpiredToken").into_poller_error("Op"),
+                   AwsPollerError::InvalidCredentials("m".to_owned()));
+        assert_eq!(error("Throttling").into_poller_error("Op"),
+                   AwsPollerError::UnknownError("Op failed with Throttling: m".to_owned()));
+    }
+
+    #[test]
+    fn finds_first_element_text() {
+        let document = "<a><Code> First </Code><Code>Second</Code></a>";
+        assert_eq!(xml_element_text(document, "Code"), Some("First"));
+        assert_eq!(xml_element_text(document, "Message"), None);
+    }
+
+    #[test]
+    fn lists_direct_items_only() {
+        let document = "<r><set><item><a>1</a><sub><item>x</item></sub></item><item><a>2</a></item></set></r>";
+        assert_eq!(xml_list_items(document, "set"),
+                   vec!["<a>1</a><sub><item>x</item></sub>", "<a>2</a>"]);
+        assert!(xml_list_items(document, "missing").is_empty());
+        let document = "<r><Values><member>1.5</member><member>2</member></Values></r>";
+        assert_eq!(xml_list_members(document, "Values"), vec!["1.5", "2"]);
+    }
+}
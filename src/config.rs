@@ -5,13 +5,16 @@ use std::time::Duration;
 use std::option::Option;
 use std::io;
 use serde_yaml;
+use serde_json::{self, Value};
 use std::fs::File;
 use std::error::Error;
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ConfigError {
     IoError(String),
     SyntaxError(String),
+    InvalidValue(String),
 }
 
 impl From<io::Error> for ConfigError {
@@ -26,13 +29,25 @@ impl From<serde_yaml::Error> for ConfigError {
     }
 }
 
-#[derive(Copy, Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub enum AwsCredentialsProviderType {
     Default,
     Environment,
     Profile,
     Instance,
-    Container
+    Container,
+    /// YAML file with `access_key_id`, `secret_access_key` and optional `session_token`.
+    Static { credentials_file: String },
+    /// Falls back to `AWS_ROLE_ARN`, `AWS_WEB_IDENTITY_TOKEN_FILE` and `AWS_ROLE_SESSION_NAME`.
+    WebIdentity {
+        role_arn: Option<String>,
+        web_identity_token_file: Option<String>,
+        role_session_name: Option<String>,
+        /// Custom STS endpoint, e.g. VPC interface endpoint, the regional one is used if not set.
+        sts_endpoint_url: Option<String>
+    },
+    /// Command printing credentials in the AWS CLI `credential_process` JSON format.
+    Process { credential_process: String }
 }
 
 impl Default for AwsCredentialsProviderType {
@@ -49,50 +64,371 @@ pub trait AwsSpotPricesPollerSettingsProvider {
     fn aws_spot_prices_poller_settings(&self) -> AwsSpotPricesPollerSettings;
 }
 
+pub trait AwsSpotRequestsPollerSettingsProvider {
+    fn aws_spot_requests_poller_settings(&self) -> Option<AwsSpotRequestsPollerSettings>;
+}
+
+pub trait AwsAccountLimitsPollerSettingsProvider {
+    fn aws_account_limits_poller_settings(&self) -> Option<AwsAccountLimitsPollerSettings>;
+}
+
+pub trait AwsInstanceStatusPollerSettingsProvider {
+    fn aws_instance_status_poller_settings(&self) -> Option<AwsInstanceStatusPollerSettings>;
+}
+
+pub trait AwsAutoScalingPollerSettingsProvider {
+    fn aws_autoscaling_poller_settings(&self) -> Option<AwsAutoScalingPollerSettings>;
+}
+
+pub trait AwsCloudWatchPollerSettingsProvider {
+    fn aws_cloudwatch_poller_settings(&self) -> Option<AwsCloudWatchPollerSettings>;
+}
+
+pub trait AwsElbPollerSettingsProvider {
+    fn aws_elb_poller_settings(&self) -> Option<AwsElbPollerSettings>;
+}
+
+pub trait AwsRdsPollerSettingsProvider {
+    fn aws_rds_poller_settings(&self) -> Option<AwsRdsPollerSettings>;
+}
+
+pub trait InstanceTypesSettingsProvider {
+    fn instance_types_settings(&self) -> InstanceTypesSettings;
+}
+
+pub trait HttpClientSettingsProvider {
+    fn http_client_settings(&self) -> HttpClientSettings;
+}
+
 pub trait ScrapeSettingsProvider {
     fn listen_on(&self) -> SocketAddr;
     fn read_timeout(&self) -> Option<Duration>;
     fn keep_alive_timeout(&self) -> Option<Duration>;
     fn polling_period(&self) -> Option<Duration>;
+    /// Poll is abandoned when it runs longer, previously collected metrics are kept.
+    fn poll_deadline(&self) -> Option<Duration>;
+    /// Same as `poll_deadline` for polls made on scrape, it should be shorter than the scrape
+    /// timeout of Prometheus.
+    fn scrape_deadline(&self) -> Option<Duration>;
+    /// Whether pollers which could not reach AWS at startup keep retrying instead of aborting.
+    fn degraded_startup(&self) -> bool;
+    /// Pollers run in the background unless configured otherwise.
+    fn poller_schedule(&self, poller_name: &str) -> PollerScheduleSettings;
+    /// Bearer token of the refresh endpoint, which is disabled if the token is not set.
+    fn refresh_token(&self) -> Option<String>;
+    /// Time to keep serving scrapes after termination was requested while reporting not ready.
+    fn shutdown_grace_period(&self) -> Option<Duration>;
+    /// Process exits with non-zero code if shutdown takes longer.
+    fn shutdown_timeout(&self) -> Option<Duration>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum PollerMode {
+    #[serde(rename = "background")]
+    Background,
+    /// Poll when metrics are scraped.
+    #[serde(rename = "on_scrape")]
+    OnScrape
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PollerScheduleSettings {
+    pub mode: Option<PollerMode>,
+    /// Seconds to reuse results of the previous poll in `on_scrape` mode, defaults to the polling period.
+    pub cache_ttl: Option<u64>
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AwsInstancesPollerSettings {
     pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>,
     pub region: String,
+    /// Polled instead of `region` when set.
+    pub regions: Option<Vec<String>>,
+    pub endpoint_url: Option<String>,
+    /// Polled with their own credentials instead of the credentials above when set.
+    pub accounts: Option<Vec<AwsAccountSettings>>,
+    /// EC2 filters which split instances into shards queried separately, they must not overlap
+    /// and should cover all instances.
+    pub shards: Option<Vec<Ec2FilterSettings>>,
+    /// Number of regions, accounts and shards queried at the same time, also limits concurrent
+    /// CloudWatch requests of the instance metrics.
+    pub max_parallel_requests: Option<usize>,
     pub expose_tags: Vec<String>,
+    pub cost_group_tags: Option<Vec<String>>,
     pub max_chunk_size: Option<i32>,
+    pub max_staleness: Option<MaxStalenessSettings>,
+    pub cloudwatch: Option<InstanceCloudWatchSettings>,
+}
+
+/// Series of a failing poller become stale after any of the limits is hit.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MaxStalenessSettings {
+    pub failed_polls: Option<u32>,
+    pub seconds: Option<u64>,
+    /// Stale series are dropped by default.
+    pub action: Option<StaleSeriesAction>
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum StaleSeriesAction {
+    Drop,
+    Mark
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AwsAccountSettings {
+    pub name: String,
+    pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ec2FilterSettings {
+    pub name: String,
+    pub values: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InstanceCloudWatchSettings {
+    pub metrics: Option<Vec<String>>,
+    pub statistic: Option<String>,
+    pub period: Option<i64>,
+    pub lag: Option<i64>,
+    /// Number of metric queries sent in a single GetMetricData call, at most 500.
+    pub batch_size: Option<usize>
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AwsSpotPricesPollerSettings {
     pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>,
     pub region: String,
+    pub endpoint_url: Option<String>,
     pub availability_zones: Option<Vec<String>>,
     pub products: Option<Vec<String>>,
     pub instance_types: Option<Vec<String>>,
     pub max_chunk_size: Option<i32>
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AwsSpotRequestsPollerSettings {
+    pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>,
+    pub region: String,
+    pub endpoint_url: Option<String>,
+    pub states: Option<Vec<String>>,
+    pub max_chunk_size: Option<i32>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AwsInstanceStatusPollerSettings {
+    pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>,
+    pub region: String,
+    pub endpoint_url: Option<String>,
+    pub include_all_instances: Option<bool>,
+    pub max_chunk_size: Option<i32>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AwsAutoScalingPollerSettings {
+    pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>,
+    pub region: String,
+    pub endpoint_url: Option<String>,
+    pub max_chunk_size: Option<i64>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CloudWatchMetricSettings {
+    pub namespace: String,
+    pub metric_name: String,
+    pub dimensions: Option<HashMap<String, String>>,
+    pub statistic: String,
+    pub period: Option<i64>,
+    pub name: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AwsCloudWatchPollerSettings {
+    pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>,
+    pub region: String,
+    pub endpoint_url: Option<String>,
+    pub lag: Option<i64>,
+    pub metrics: Vec<CloudWatchMetricSettings>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AwsElbPollerSettings {
+    pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>,
+    pub region: String,
+    pub endpoint_url: Option<String>,
+    pub max_chunk_size: Option<i64>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AwsRdsPollerSettings {
+    pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>,
+    pub region: String,
+    pub endpoint_url: Option<String>,
+    pub expose_tags: Option<Vec<String>>,
+    pub max_chunk_size: Option<i64>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InstanceTypeSettings {
+    pub vcpus: u32,
+    pub memory_mib: u32,
+    pub on_demand_price: Option<HashMap<String, f64>>
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct InstanceTypesSettings {
+    pub catalogue_file: Option<String>,
+    pub on_demand_prices_file: Option<String>,
+    pub refresh_period: Option<u64>,
+    /// Region to list instance types in with `DescribeInstanceTypes`. The region, credentials
+    /// and endpoint of the instances poller are used if not set.
+    pub region: Option<String>,
+    pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>,
+    pub endpoint_url: Option<String>,
+    pub instance_types: Option<HashMap<String, InstanceTypeSettings>>
+}
+
+/// Connection settings of HTTP clients used to call AWS APIs, timeouts are in seconds.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct HttpClientSettings {
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub write_timeout: Option<u64>,
+    /// Limits a single API call including retries of reads and writes.
+    pub request_timeout: Option<u64>,
+    pub max_idle_connections: Option<usize>,
+    /// Limits API calls made with the same credentials by all pollers, unlimited if not set.
+    pub requests_per_second: Option<f64>
+}
+
+impl HttpClientSettings {
+    /// Zero timeouts are rejected by sockets and a pool without idle connections defeats its purpose.
+    /// A rate limit which lets no requests through would stall all pollers.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(rps) = self.requests_per_second {
+            if !(rps > 0.0) {
+                return Err(ConfigError::InvalidValue(format!(
+                    "http_client_settings.requests_per_second must be positive, got {}", rps)));
+            }
+        }
+        let settings = [("connect_timeout", self.connect_timeout), ("read_timeout", self.read_timeout),
+                        ("write_timeout", self.write_timeout), ("request_timeout", self.request_timeout),
+                        ("max_idle_connections", self.max_idle_connections.map(|n| n as u64))];
+        match settings.iter().find(|&&(_, value)| value == Some(0)) {
+            Some(&(name, _)) =>
+                Err(ConfigError::InvalidValue(format!("http_client_settings.{} must be positive", name))),
+            None => Ok(())
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AwsAccountLimitsPollerSettings {
+    pub credentials_provider: Option<AwsCredentialsProviderType>,
+    pub profile_name: Option<String>,
+    pub credentials_file: Option<String>,
+    pub region: String,
+    pub endpoint_url: Option<String>,
+    pub max_chunk_size: Option<i32>
+}
+
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS: u64 = 5;
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
 #[derive(Serialize, Deserialize)]
 struct  ScrapeSettings {
     polling_period: Option<u64>,
     listen_on: SocketAddr,
     read_timeout: Option<u64>,
     keep_alive_timeout: Option<u64>,
+    degraded_startup: Option<bool>,
+    poll_deadline: Option<u64>,
+    scrape_deadline: Option<u64>,
+    pollers: Option<HashMap<String, PollerScheduleSettings>>,
+    refresh_token: Option<String>,
+    shutdown_grace_period: Option<u64>,
+    shutdown_timeout: Option<u64>,
+}
+
+impl ScrapeSettings {
+    /// Scrapes are served during the grace period, so the process must not be killed by then.
+    fn validate(&self) -> Result<(), ConfigError> {
+        let grace_period = self.shutdown_grace_period.unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS);
+        let timeout = self.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+        if grace_period >= timeout {
+            return Err(ConfigError::InvalidValue(format!(
+                "scrape_settings.shutdown_grace_period ({}) must be shorter than shutdown_timeout ({})",
+                grace_period, timeout)));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DeucalionSettings {
     aws_instances_poller_settings: AwsInstancesPollerSettings,
     aws_spot_prices_poller_settings: AwsSpotPricesPollerSettings,
+    aws_spot_requests_poller_settings: Option<AwsSpotRequestsPollerSettings>,
+    aws_account_limits_poller_settings: Option<AwsAccountLimitsPollerSettings>,
+    aws_instance_status_poller_settings: Option<AwsInstanceStatusPollerSettings>,
+    aws_autoscaling_poller_settings: Option<AwsAutoScalingPollerSettings>,
+    aws_cloudwatch_poller_settings: Option<AwsCloudWatchPollerSettings>,
+    aws_elb_poller_settings: Option<AwsElbPollerSettings>,
+    aws_rds_poller_settings: Option<AwsRdsPollerSettings>,
+    instance_types_settings: Option<InstanceTypesSettings>,
+    http_client_settings: Option<HttpClientSettings>,
     scrape_settings: ScrapeSettings
 }
 
 impl DeucalionSettings {
     pub fn from_filename(filename: &str) -> Result<Self, ConfigError>
     {
-        Ok(serde_yaml::from_reader(File::open(filename)?)?)
+        let settings: DeucalionSettings = serde_yaml::from_reader(File::open(filename)?)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(ref s) = self.http_client_settings {
+            s.validate()?;
+        }
+        self.scrape_settings.validate()
+    }
+
+    /// Scrape, HTTP client and instance types settings, which are applied on restart only.
+    /// Poller schedules are left out as they are reloaded together with pollers.
+    pub fn restart_only_settings(&self) -> Value {
+        let mut scrape_settings = serde_json::to_value(&self.scrape_settings).unwrap_or(Value::Null);
+        if let Some(s) = scrape_settings.as_object_mut() {
+            s.remove("pollers");
+        }
+        Value::Array(vec![
+            scrape_settings,
+            serde_json::to_value(&self.http_client_settings).unwrap_or(Value::Null),
+            serde_json::to_value(&self.instance_types_settings).unwrap_or(Value::Null)
+        ])
     }
 }
 
@@ -108,6 +444,60 @@ impl AwsSpotPricesPollerSettingsProvider for DeucalionSettings {
     }
 }
 
+impl AwsSpotRequestsPollerSettingsProvider for DeucalionSettings {
+    fn aws_spot_requests_poller_settings(&self) -> Option<AwsSpotRequestsPollerSettings> {
+        self.aws_spot_requests_poller_settings.clone()
+    }
+}
+
+impl AwsAccountLimitsPollerSettingsProvider for DeucalionSettings {
+    fn aws_account_limits_poller_settings(&self) -> Option<AwsAccountLimitsPollerSettings> {
+        self.aws_account_limits_poller_settings.clone()
+    }
+}
+
+impl AwsInstanceStatusPollerSettingsProvider for DeucalionSettings {
+    fn aws_instance_status_poller_settings(&self) -> Option<AwsInstanceStatusPollerSettings> {
+        self.aws_instance_status_poller_settings.clone()
+    }
+}
+
+impl AwsAutoScalingPollerSettingsProvider for DeucalionSettings {
+    fn aws_autoscaling_poller_settings(&self) -> Option<AwsAutoScalingPollerSettings> {
+        self.aws_autoscaling_poller_settings.clone()
+    }
+}
+
+impl AwsCloudWatchPollerSettingsProvider for DeucalionSettings {
+    fn aws_cloudwatch_poller_settings(&self) -> Option<AwsCloudWatchPollerSettings> {
+        self.aws_cloudwatch_poller_settings.clone()
+    }
+}
+
+impl AwsElbPollerSettingsProvider for DeucalionSettings {
+    fn aws_elb_poller_settings(&self) -> Option<AwsElbPollerSettings> {
+        self.aws_elb_poller_settings.clone()
+    }
+}
+
+impl AwsRdsPollerSettingsProvider for DeucalionSettings {
+    fn aws_rds_poller_settings(&self) -> Option<AwsRdsPollerSettings> {
+        self.aws_rds_poller_settings.clone()
+    }
+}
+
+impl InstanceTypesSettingsProvider for DeucalionSettings {
+    fn instance_types_settings(&self) -> InstanceTypesSettings {
+        self.instance_types_settings.clone().unwrap_or_default()
+    }
+}
+
+impl HttpClientSettingsProvider for DeucalionSettings {
+    fn http_client_settings(&self) -> HttpClientSettings {
+        self.http_client_settings.clone().unwrap_or_default()
+    }
+}
+
 impl ScrapeSettingsProvider for DeucalionSettings {
     fn listen_on(&self) -> SocketAddr {
         self.scrape_settings.listen_on
@@ -124,4 +514,72 @@ impl ScrapeSettingsProvider for DeucalionSettings {
     fn polling_period(&self) -> Option<Duration> {
         self.scrape_settings.polling_period.map(Duration::from_secs)
     }
+
+    fn poll_deadline(&self) -> Option<Duration> {
+        self.scrape_settings.poll_deadline.map(Duration::from_secs)
+    }
+
+    fn scrape_deadline(&self) -> Option<Duration> {
+        self.scrape_settings.scrape_deadline.map(Duration::from_secs)
+    }
+
+    fn degraded_startup(&self) -> bool {
+        self.scrape_settings.degraded_startup.unwrap_or(false)
+    }
+
+    fn poller_schedule(&self, poller_name: &str) -> PollerScheduleSettings {
+        self.scrape_settings.pollers.as_ref()
+            .and_then(|p| p.get(poller_name).cloned())
+            .unwrap_or_default()
+    }
+
+    fn refresh_token(&self) -> Option<String> {
+        self.scrape_settings.refresh_token.clone()
+    }
+
+    fn shutdown_grace_period(&self) -> Option<Duration> {
+        self.scrape_settings.shutdown_grace_period.map(Duration::from_secs)
+    }
+
+    fn shutdown_timeout(&self) -> Option<Duration> {
+        self.scrape_settings.shutdown_timeout.map(Duration::from_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_zero_http_client_settings() {
+        let mut settings = HttpClientSettings::default();
+        assert_eq!(settings.validate(), Ok(()));
+        settings.connect_timeout = Some(10);
+        settings.max_idle_connections = Some(0);
+        assert_eq!(settings.validate(), Err(ConfigError::InvalidValue(
+            "http_client_settings.max_idle_connections must be positive".to_owned())));
+    }
+
+    #[test]
+    fn rejects_non_positive_requests_per_second() {
+        let mut settings = HttpClientSettings::default();
+        settings.requests_per_second = Some(0.5);
+        assert_eq!(settings.validate(), Ok(()));
+        settings.requests_per_second = Some(0.0);
+        assert_eq!(settings.validate(), Err(ConfigError::InvalidValue(
+            "http_client_settings.requests_per_second must be positive, got 0".to_owned())));
+        settings.requests_per_second = Some(-1.0);
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_grace_period_not_shorter_than_shutdown_timeout() {
+        let settings = |yaml: &str| serde_yaml::from_str::<ScrapeSettings>(yaml).unwrap();
+        assert_eq!(settings("listen_on: 0.0.0.0:8082").validate(), Ok(()));
+        assert_eq!(settings("{ listen_on: '0.0.0.0:8082', shutdown_grace_period: 10, shutdown_timeout: 15 }")
+                       .validate(), Ok(()));
+        assert_eq!(settings("{ listen_on: '0.0.0.0:8082', shutdown_grace_period: 30 }").validate(),
+                   Err(ConfigError::InvalidValue("scrape_settings.shutdown_grace_period (30) must be shorter \
+                                                  than shutdown_timeout (30)".to_owned())));
+    }
 }
@@ -0,0 +1,555 @@
+use config::AwsCredentialsProviderType;
+use aws_poller::{AwsPollerError, PollerResult};
+use aws_connector::{HttpClientFactory, SharedHttpClient};
+use aws_request::{self, xml_element_text};
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::fs::{self, File};
+use std::io::{stderr, Read, Write};
+use std::env;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+use std::time::SystemTime;
+use rusoto::{ProvideAwsCredentials, AwsCredentials, DefaultCredentialsProviderSync, EnvironmentProvider,
+             ProfileProvider, InstanceMetadataProvider, ContainerProvider, CredentialsError, Region,
+             SignedRequest};
+use url::form_urlencoded;
+use chrono::{DateTime, UTC, Duration, TimeZone};
+use serde_yaml;
+use serde_json;
+use prometheus::{Opts, GaugeVec, Collector};
+use deadline;
+
+/// Credentials which expire sooner than this are refreshed in advance.
+const EXPIRY_MARGIN_SECS: i64 = 300;
+
+#[derive(Clone)]
+struct CredentialsSettings {
+    provider_type: AwsCredentialsProviderType,
+    profile_name: Option<String>,
+    credentials_file: Option<String>,
+    /// Region of the STS endpoint web identity tokens are exchanged at.
+    region: Region,
+    sts_http_client: Option<SharedHttpClient>
+}
+
+impl CredentialsSettings {
+    /// Identifies credentials source, pollers with the same key act on behalf of the same account.
+    fn key(&self) -> String {
+        match self.provider_type {
+            AwsCredentialsProviderType::Default => "default".to_owned(),
+            AwsCredentialsProviderType::Environment => "environment".to_owned(),
+            AwsCredentialsProviderType::Profile => format!("profile:{}:{}",
+                self.profile_name.as_ref().map_or("default", |s| &**s),
+                self.credentials_file.as_ref().map_or("", |s| &**s)),
+            AwsCredentialsProviderType::Instance => "instance".to_owned(),
+            AwsCredentialsProviderType::Container => "container".to_owned(),
+            AwsCredentialsProviderType::Static { ref credentials_file } =>
+                format!("static:{}", credentials_file),
+            AwsCredentialsProviderType::WebIdentity { ref role_arn, .. } =>
+                format!("web_identity:{}", role_arn.as_ref().map_or("", |s| &**s)),
+            AwsCredentialsProviderType::Process { ref credential_process } =>
+                format!("process:{}", credential_process)
+        }
+    }
+
+    /// Names the credentials source in metrics. Profiles, files, roles and commands of the key
+    /// are replaced with a short hash, as they may reveal paths or secrets passed as arguments.
+    fn label(&self) -> String {
+        let key = self.key();
+        match key.find(':') {
+            Some(i) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                format!("{}:{:08x}", &key[..i], hasher.finish() as u32)
+            }
+            None => key
+        }
+    }
+}
+
+type SharedProvider = Arc<ProvideAwsCredentials + Send + Sync>;
+
+/// Credentials provider shared by all clients of a poller. The underlying provider is rebuilt
+/// from settings when AWS rejects credentials, e.g. after keys rotation.
+#[derive(Clone)]
+pub struct CredentialsProviderWrapper {
+    settings: CredentialsSettings,
+    /// The provider and whether its credentials expire.
+    inner: Arc<RwLock<(SharedProvider, bool)>>,
+    expiry: GaugeVec
+}
+
+impl CredentialsProviderWrapper {
+    /// `profile_name` and `credentials_file` select the profile for `Profile` provider, which is
+    /// also the default one when any of them is set. Web identity tokens are exchanged at the STS
+    /// endpoint of the poller `region`.
+    pub fn from_type(poller_name: &str, provider_type: Option<AwsCredentialsProviderType>,
+                     profile_name: Option<String>, credentials_file: Option<String>,
+                     region: Region, http_clients: &HttpClientFactory)
+                     -> PollerResult<CredentialsProviderWrapper> {
+        let provider_type = provider_type.unwrap_or(
+            if profile_name.is_some() || credentials_file.is_some() {
+                AwsCredentialsProviderType::Profile
+            } else {
+                AwsCredentialsProviderType::Default
+            });
+        let sts_http_client = match provider_type {
+            AwsCredentialsProviderType::WebIdentity { ref sts_endpoint_url, .. } =>
+                Some(http_clients.credentials_client(sts_endpoint_url.as_ref().map(|s| &**s))?),
+            _ => None
+        };
+        let settings = CredentialsSettings {
+            provider_type: provider_type,
+            profile_name: profile_name,
+            credentials_file: credentials_file,
+            region: region,
+            sts_http_client: sts_http_client
+        };
+        Ok(CredentialsProviderWrapper {
+            inner: Arc::new(RwLock::new(Self::build(&settings)?)),
+            settings: settings,
+            expiry: GaugeVec::new(
+                Opts::new("deucalion_credentials_expiry_timestamp_seconds",
+                          "Expiration time of temporary AWS credentials used by the poller")
+                    .const_label("poller", poller_name),
+                &[])?
+        })
+    }
+
+    /// Environment and profile credentials are long-lived keys, rusoto reports them with a made up
+    /// expiration time, so it is exported only for providers of temporary credentials.
+    fn build(settings: &CredentialsSettings) -> StdResult<(SharedProvider, bool), CredentialsError> {
+        let expires = match settings.provider_type {
+            AwsCredentialsProviderType::Environment | AwsCredentialsProviderType::Profile
+            | AwsCredentialsProviderType::Static { .. } => false,
+            // the default chain tries environment and profile before the metadata services
+            AwsCredentialsProviderType::Default => EnvironmentProvider {}.credentials().is_err()
+                && ProfileProvider::new().and_then(|p| p.credentials()).is_err(),
+            _ => true
+        };
+        let provider: SharedProvider = match settings.provider_type.clone() {
+            AwsCredentialsProviderType::Default =>
+                Arc::new(DefaultCredentialsProviderSync::new()?),
+            AwsCredentialsProviderType::Environment =>
+                Arc::new(EnvironmentProvider {}),
+            AwsCredentialsProviderType::Profile => {
+                let mut provider = match settings.credentials_file {
+                    Some(ref f) => ProfileProvider::with_configuration(f.clone(), "default"),
+                    None => ProfileProvider::new()?
+                };
+                if let Some(ref p) = settings.profile_name {
+                    provider.set_profile(p.clone());
+                }
+                Arc::new(provider)
+            }
+            AwsCredentialsProviderType::Instance =>
+                Arc::new(InstanceMetadataProvider {}),
+            AwsCredentialsProviderType::Container =>
+                Arc::new(ContainerProvider {}),
+            AwsCredentialsProviderType::Static { credentials_file } =>
+                Arc::new(StaticFileProvider::new(credentials_file)),
+            AwsCredentialsProviderType::WebIdentity { role_arn, web_identity_token_file, role_session_name, .. } =>
+                Arc::new(CachingProvider::new(WebIdentityProvider::new(
+                    role_arn, web_identity_token_file, role_session_name, settings.region,
+                    settings.sts_http_client.clone()
+                        .ok_or(CredentialsError::new("STS client is not configured".to_owned()))?)?)),
+            AwsCredentialsProviderType::Process { credential_process } =>
+                Arc::new(CachingProvider::new(CredentialProcessProvider {
+                    command: credential_process
+                }))
+        };
+        Ok((provider, expires))
+    }
+
+    /// Try to retrieve credentials from provider to be able to fail-fast if credentials
+    /// are not available.
+    pub fn test(&self) -> Option<AwsPollerError> {
+        self.credentials().err().map(|e| AwsPollerError::from(e))
+    }
+
+    /// Rebuilds the provider and re-validates credentials if `err` shows that they were rejected.
+    pub fn revalidate_on(&self, err: &AwsPollerError) {
+        if let AwsPollerError::InvalidCredentials(_) = *err {
+            let result = Self::build(&self.settings)
+                .map_err(AwsPollerError::from)
+                .and_then(|provider| {
+                    *self.inner.write().unwrap() = provider;
+                    self.test().map_or(Ok(()), Err)
+                });
+            match result {
+                Ok(()) => println!("AWS credentials were re-validated"),
+                Err(e) => { let _ = writeln!(&mut stderr(), "AWS credentials re-validation failed: {:?}", e); }
+            }
+        }
+    }
+
+    pub fn key(&self) -> String {
+        self.settings.key()
+    }
+
+    pub fn label(&self) -> String {
+        self.settings.label()
+    }
+
+    pub fn counters(&self) -> Box<Collector> {
+        Box::new(self.expiry.clone())
+    }
+}
+
+impl ProvideAwsCredentials for CredentialsProviderWrapper {
+    fn credentials(&self) -> StdResult<AwsCredentials, CredentialsError> {
+        let (provider, expires) = self.inner.read().unwrap().clone();
+        let credentials = provider.credentials()?;
+        if expires && *credentials.expires_at() != never_expires() {
+            self.expiry.with_label_values(&[]).set(credentials.expires_at().timestamp() as f64);
+        } else {
+            let _ = self.expiry.remove(&HashMap::new());
+        }
+        Ok(credentials)
+    }
+}
+
+/// Keeps credentials of the inner provider until they are about to expire.
+struct CachingProvider<P: ProvideAwsCredentials> {
+    inner: P,
+    cached: Mutex<Option<AwsCredentials>>
+}
+
+impl<P: ProvideAwsCredentials> CachingProvider<P> {
+    fn new(inner: P) -> CachingProvider<P> {
+        CachingProvider {
+            inner: inner,
+            cached: Mutex::new(None)
+        }
+    }
+}
+
+impl<P: ProvideAwsCredentials> ProvideAwsCredentials for CachingProvider<P> {
+    fn credentials(&self) -> StdResult<AwsCredentials, CredentialsError> {
+        let mut cached = self.cached.lock().unwrap();
+        let refresh_after = UTC::now() + Duration::seconds(EXPIRY_MARGIN_SECS);
+        match *cached {
+            Some(ref c) if *c.expires_at() > refresh_after => return Ok(c.clone()),
+            _ => {}
+        }
+        let fresh = self.inner.credentials()?;
+        *cached = Some(fresh.clone());
+        Ok(fresh)
+    }
+}
+
+/// Expiration time of credentials which never expire, e.g. static keys.
+fn never_expires() -> DateTime<UTC> {
+    UTC.ymd(9999, 12, 31).and_hms(0, 0, 0)
+}
+
+fn parse_expiration(expiration: &str) -> StdResult<DateTime<UTC>, CredentialsError> {
+    DateTime::parse_from_rfc3339(expiration)
+        .map(|d| d.with_timezone(&UTC))
+        .map_err(|e| CredentialsError::new(format!("Invalid credentials expiration {:?}: {}", expiration, e)))
+}
+
+fn read_file(filename: &str) -> StdResult<String, CredentialsError> {
+    let mut content = String::new();
+    File::open(filename)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| CredentialsError::new(format!("Could not read {}: {}", filename, e)))?;
+    Ok(content)
+}
+
+#[derive(Deserialize)]
+struct StaticCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>
+}
+
+/// Reads static keys from YAML file with `access_key_id`, `secret_access_key` and optional
+/// `session_token` fields. The file is read again once it is modified, e.g. when keys are rotated.
+struct StaticFileProvider {
+    credentials_file: String,
+    cached: Mutex<Option<(SystemTime, AwsCredentials)>>
+}
+
+impl StaticFileProvider {
+    fn new(credentials_file: String) -> StaticFileProvider {
+        StaticFileProvider {
+            credentials_file: credentials_file,
+            cached: Mutex::new(None)
+        }
+    }
+
+    fn read(&self) -> StdResult<AwsCredentials, CredentialsError> {
+        let c: StaticCredentials = serde_yaml::from_str(&read_file(&self.credentials_file)?)
+            .map_err(|e| CredentialsError::new(format!("Invalid {}: {}", self.credentials_file, e)))?;
+        Ok(AwsCredentials::new(c.access_key_id, c.secret_access_key, c.session_token, never_expires()))
+    }
+}
+
+impl ProvideAwsCredentials for StaticFileProvider {
+    fn credentials(&self) -> StdResult<AwsCredentials, CredentialsError> {
+        let modified = fs::metadata(&self.credentials_file)
+            .and_then(|m| m.modified())
+            .map_err(|e| CredentialsError::new(format!("Could not read {}: {}", self.credentials_file, e)))?;
+        let mut cached = self.cached.lock().unwrap();
+        match *cached {
+            Some((ref m, ref c)) if *m == modified => return Ok(c.clone()),
+            _ => {}
+        }
+        let fresh = self.read()?;
+        *cached = Some((modified, fresh.clone()));
+        Ok(fresh)
+    }
+}
+
+/// Exchanges the web identity token (e.g. EKS service account token) for role credentials with
+/// `AssumeRoleWithWebIdentity` at the regional STS endpoint. The request is not signed.
+struct WebIdentityProvider {
+    role_arn: String,
+    web_identity_token_file: String,
+    role_session_name: String,
+    region: Region,
+    http_client: SharedHttpClient
+}
+
+impl WebIdentityProvider {
+    /// Unset parameters are taken from `AWS_ROLE_ARN`, `AWS_WEB_IDENTITY_TOKEN_FILE` and
+    /// `AWS_ROLE_SESSION_NAME` environment variables.
+    fn new(role_arn: Option<String>, web_identity_token_file: Option<String>, role_session_name: Option<String>,
+           region: Region, http_client: SharedHttpClient) -> StdResult<WebIdentityProvider, CredentialsError> {
+        let from_env = |name: &str| env::var(name)
+            .map_err(|_| CredentialsError::new(format!("{} is not set", name)));
+        Ok(WebIdentityProvider {
+            role_arn: match role_arn { Some(r) => r, None => from_env("AWS_ROLE_ARN")? },
+            web_identity_token_file: match web_identity_token_file {
+                Some(f) => f,
+                None => from_env("AWS_WEB_IDENTITY_TOKEN_FILE")?
+            },
+            role_session_name: role_session_name
+                .or(env::var("AWS_ROLE_SESSION_NAME").ok())
+                .unwrap_or("deucalion".to_owned()),
+            region: region,
+            http_client: http_client
+        })
+    }
+}
+
+fn parse_assume_role_response(document: &str) -> StdResult<AwsCredentials, CredentialsError> {
+    let element = |name: &str| xml_element_text(document, name)
+        .map(|s| s.to_owned())
+        .ok_or(CredentialsError::new(format!("{} is missing in AssumeRoleWithWebIdentity response", name)));
+    Ok(AwsCredentials::new(element("AccessKeyId")?, element("SecretAccessKey")?,
+                           Some(element("SessionToken")?), parse_expiration(&element("Expiration")?)?))
+}
+
+impl ProvideAwsCredentials for WebIdentityProvider {
+    fn credentials(&self) -> StdResult<AwsCredentials, CredentialsError> {
+        // the token is rotated by kubelet, so it is re-read on every refresh
+        let token = read_file(&self.web_identity_token_file)?;
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("Action", "AssumeRoleWithWebIdentity")
+            .append_pair("Version", "2011-06-15")
+            .append_pair("RoleArn", &self.role_arn)
+            .append_pair("RoleSessionName", &self.role_session_name)
+            .append_pair("WebIdentityToken", token.trim())
+            .finish();
+        let mut request = SignedRequest::new("POST", "sts", self.region, "/");
+        request.set_content_type("application/x-www-form-urlencoded".to_owned());
+        request.set_payload(Some(body.into_bytes()));
+        match aws_request::send(&self.http_client, &request) {
+            Ok(Ok(document)) => parse_assume_role_response(&document),
+            Ok(Err(e)) => Err(CredentialsError::new(format!("AssumeRoleWithWebIdentity failed: {}", e.message))),
+            Err(e) => Err(CredentialsError::new(format!("AssumeRoleWithWebIdentity failed: {}", e)))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProcessCredentials {
+    #[serde(rename = "Version")]
+    version: i32,
+    #[serde(rename = "AccessKeyId")]
+    access_key_id: String,
+    #[serde(rename = "SecretAccessKey")]
+    secret_access_key: String,
+    #[serde(rename = "SessionToken")]
+    session_token: Option<String>,
+    #[serde(rename = "Expiration")]
+    expiration: Option<String>
+}
+
+/// Longest time a credential process may run, shortened by the deadline of the calling thread.
+const CREDENTIAL_PROCESS_TIMEOUT_SECONDS: u64 = 60;
+
+/// Runs an external command which prints credentials in the AWS CLI `credential_process` format.
+struct CredentialProcessProvider {
+    command: String
+}
+
+impl ProvideAwsCredentials for CredentialProcessProvider {
+    fn credentials(&self) -> StdResult<AwsCredentials, CredentialsError> {
+        let mut timeout = StdDuration::from_secs(CREDENTIAL_PROCESS_TIMEOUT_SECONDS);
+        if let Some(remaining) = deadline::remaining() {
+            if remaining < timeout {
+                timeout = remaining;
+            }
+        }
+        let (stdout, stderr) = run_with_timeout(Command::new("sh").arg("-c").arg(&self.command), timeout)?;
+        match stderr {
+            Some(e) => Err(CredentialsError::new(e)),
+            None => parse_process_credentials(&stdout)
+        }
+    }
+}
+
+/// Runs the command and returns its output, or the description of its failure along with
+/// its error output. The command is killed if it does not exit within `timeout`.
+fn run_with_timeout(command: &mut Command, timeout: StdDuration)
+                    -> StdResult<(Vec<u8>, Option<String>), CredentialsError> {
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+        .map_err(|e| CredentialsError::new(format!("Could not run credential process: {}", e)))?;
+    // pipes are drained while waiting, so that a verbose process does not block on a full pipe
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+    let started = Instant::now();
+    let status;
+    loop {
+        match child.try_wait() {
+            Ok(Some(s)) => { status = s; break; }
+            Ok(None) if started.elapsed() >= timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(CredentialsError::new(format!("Credential process did not exit within {} ms",
+                    timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1000000)));
+            }
+            Ok(None) => thread::sleep(StdDuration::from_millis(10)),
+            Err(e) => return Err(CredentialsError::new(format!("Could not wait for credential process: {}", e)))
+        }
+    }
+    let stdout = stdout.join().unwrap_or(Vec::new());
+    let stderr = stderr.join().unwrap_or(Vec::new());
+    if status.success() {
+        Ok((stdout, None))
+    } else {
+        Ok((stdout, Some(format!("Credential process failed with {}: {}", status, String::from_utf8_lossy(&stderr)))))
+    }
+}
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        output
+    })
+}
+
+fn parse_process_credentials(output: &[u8]) -> StdResult<AwsCredentials, CredentialsError> {
+    let c: ProcessCredentials = serde_json::from_slice(output)
+        .map_err(|e| CredentialsError::new(format!("Invalid credential process output: {}", e)))?;
+    if c.version != 1 {
+        return Err(CredentialsError::new(format!("Unsupported credential process version {}", c.version)));
+    }
+    let expires_at = match c.expiration {
+        Some(ref e) => parse_expiration(e)?,
+        None => never_expires()
+    };
+    Ok(AwsCredentials::new(c.access_key_id, c.secret_access_key, c.session_token, expires_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_assume_role_response() {
+        let document = "<AssumeRoleWithWebIdentityResponse><AssumeRoleWithWebIdentityResult><Credentials>\
+            <SessionToken>token</SessionToken><SecretAccessKey>secret</SecretAccessKey>\
+            <Expiration>2019-11-01T12:00:00Z</Expiration><AccessKeyId>ASIA1</AccessKeyId>\
+            </Credentials></AssumeRoleWithWebIdentityResult></AssumeRoleWithWebIdentityResponse>";
+        let credentials = parse_assume_role_response(document).unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "ASIA1");
+        assert_eq!(credentials.aws_secret_access_key(), "secret");
+        assert_eq!(credentials.token(), &Some("token".to_owned()));
+        assert_eq!(credentials.expires_at().timestamp(), 1572609600);
+    }
+
+    #[test]
+    fn rejects_incomplete_assume_role_response() {
+        assert!(parse_assume_role_response("<Credentials><AccessKeyId>ASIA1</AccessKeyId></Credentials>").is_err());
+    }
+
+    #[test]
+    fn parses_process_credentials() {
+        let output = br#"{"Version": 1, "AccessKeyId": "AKIA1", "SecretAccessKey": "secret",
+                          "Expiration": "2019-11-01T12:00:00Z"}"#;
+        let credentials = parse_process_credentials(output).unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "AKIA1");
+        assert_eq!(credentials.token(), &None);
+        assert_eq!(credentials.expires_at().timestamp(), 1572609600);
+    }
+
+    #[test]
+    fn rejects_unsupported_process_credentials_version() {
+        let output = br#"{"Version": 2, "AccessKeyId": "AKIA1", "SecretAccessKey": "secret"}"#;
+        assert!(parse_process_credentials(output).is_err());
+    }
+
+    #[test]
+    fn returns_credential_process_output() {
+        let (stdout, stderr) = run_with_timeout(Command::new("sh").arg("-c").arg("echo ok"),
+                                                StdDuration::from_secs(10)).unwrap();
+        assert_eq!(stdout, b"ok\n".to_vec());
+        assert_eq!(stderr, None);
+        let (_, stderr) = run_with_timeout(Command::new("sh").arg("-c").arg("echo denied >&2; exit 3"),
+                                           StdDuration::from_secs(10)).unwrap();
+        assert!(stderr.unwrap().contains("denied"));
+    }
+
+    #[test]
+    fn kills_hung_credential_process() {
+        let started = Instant::now();
+        assert!(run_with_timeout(Command::new("sleep").arg("10"), StdDuration::from_millis(100)).is_err());
+        assert!(started.elapsed() < StdDuration::from_secs(5));
+    }
+
+    fn settings(provider_type: AwsCredentialsProviderType, profile_name: Option<&str>, credentials_file: Option<&str>)
+                -> CredentialsSettings {
+        CredentialsSettings {
+            provider_type: provider_type,
+            profile_name: profile_name.map(|s| s.to_owned()),
+            credentials_file: credentials_file.map(|s| s.to_owned()),
+            region: Region::UsEast1,
+            sts_http_client: None
+        }
+    }
+
+    #[test]
+    fn profiles_of_different_files_have_different_keys() {
+        let profile = |name, file| settings(AwsCredentialsProviderType::Profile, name, file).key();
+        assert_eq!(profile(None, None), "profile:default:");
+        assert_eq!(profile(Some("prod"), Some("/etc/aws")), "profile:prod:/etc/aws");
+        assert!(profile(Some("prod"), None) != profile(Some("prod"), Some("/etc/aws")));
+        assert_eq!(settings(AwsCredentialsProviderType::Default, None, None).key(), "default");
+    }
+
+    #[test]
+    fn labels_do_not_expose_credentials_sources() {
+        let process = |command: &str| settings(AwsCredentialsProviderType::Process {
+            credential_process: command.to_owned()
+        }, None, None).label();
+        let label = process("/usr/bin/vault-aws --token s3cr3t");
+        assert!(label.starts_with("process:") && !label.contains("s3cr3t"), "label {}", label);
+        assert_eq!(label, process("/usr/bin/vault-aws --token s3cr3t"));
+        assert!(label != process("/usr/bin/vault-aws --token other"));
+        assert!(!settings(AwsCredentialsProviderType::Profile, Some("prod"), Some("/etc/aws")).label()
+                    .contains("/etc/aws"));
+        assert_eq!(settings(AwsCredentialsProviderType::Instance, None, None).label(), "instance");
+    }
+}
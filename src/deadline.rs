@@ -0,0 +1,95 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::time::{Duration, Instant};
+
+/// Message of I/O errors caused by an exceeded deadline.
+pub const DEADLINE_EXCEEDED: &'static str = "deadline exceeded";
+
+thread_local!(static DEADLINE: Cell<Option<Instant>> = Cell::new(None));
+
+static CANCELLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Exceeds deadlines of all threads at once, so that running polls fail on their next API call,
+/// e.g. between pages, instead of running to completion during shutdown.
+pub fn cancel_all() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Runs `f` with a deadline for all AWS API calls made by the current thread. A nested deadline
+/// can only shorten the outer one.
+pub fn with_deadline<T, F: FnOnce() -> T>(deadline: Instant, f: F) -> T {
+    let outer = current();
+    let effective = match outer {
+        Some(d) if d < deadline => d,
+        _ => deadline
+    };
+    DEADLINE.with(|d| d.set(Some(effective)));
+    let _restore = RestoreDeadline(outer);
+    f()
+}
+
+/// Restores the outer deadline when `with_deadline` returns, even if `f` panics.
+struct RestoreDeadline(Option<Instant>);
+
+impl Drop for RestoreDeadline {
+    fn drop(&mut self) {
+        DEADLINE.with(|d| d.set(self.0));
+    }
+}
+
+/// Deadline of the current thread, if any. Threads spawned during a poll should run under
+/// the same deadline.
+pub fn current() -> Option<Instant> {
+    DEADLINE.with(|d| d.get())
+}
+
+/// Time left until the deadline of the current thread, zero if it is exceeded or cancelled.
+pub fn remaining() -> Option<Duration> {
+    if CANCELLED.load(Ordering::SeqCst) {
+        return Some(Duration::from_secs(0));
+    }
+    current().map(|d| {
+        let now = Instant::now();
+        if d > now { d - now } else { Duration::from_secs(0) }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    #[test]
+    fn nested_deadline_only_shortens_the_outer_one() {
+        let now = Instant::now();
+        let outer = now + Duration::from_secs(10);
+        assert_eq!(current(), None);
+        with_deadline(outer, || {
+            assert_eq!(current(), Some(outer));
+            with_deadline(now + Duration::from_secs(20), || assert_eq!(current(), Some(outer)));
+            with_deadline(now + Duration::from_secs(5), || assert_eq!(current(), Some(now + Duration::from_secs(5))));
+            assert_eq!(current(), Some(outer));
+        });
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn restores_outer_deadline_after_panic() {
+        let outer = Instant::now() + Duration::from_secs(10);
+        with_deadline(outer, || {
+            let result = panic::catch_unwind(|| {
+                with_deadline(Instant::now(), || panic!("poll failed"))
+            });
+            assert!(result.is_err());
+            assert_eq!(current(), Some(outer));
+        });
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn exceeded_deadline_leaves_no_time() {
+        let past = Instant::now();
+        with_deadline(past, || assert_eq!(remaining(), Some(Duration::from_secs(0))));
+        assert_eq!(remaining(), None);
+    }
+}
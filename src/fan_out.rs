@@ -0,0 +1,74 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use deadline;
+
+/// Runs `task` for every input on at most `workers` threads and returns results in the order of
+/// inputs. Workers run under the deadline of the calling thread, so that they are abandoned
+/// together with the poll. A panicking task yields `None`.
+pub fn fan_out<T, R, F>(inputs: Vec<T>, workers: usize, task: F) -> Vec<Option<R>>
+    where T: Send + 'static, R: Send + 'static, F: Fn(T) -> R + Send + Sync + 'static
+{
+    let count = inputs.len();
+    let queue = Arc::new(Mutex::new(inputs.into_iter().enumerate()));
+    let task = Arc::new(task);
+    let (sender, receiver) = channel();
+    let poll_deadline = deadline::current();
+    let handles: Vec<_> = (0..workers.max(1).min(count))
+        .map(|_| {
+            let queue = queue.clone();
+            let task = task.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let work = || loop {
+                    let next = queue.lock().unwrap().next();
+                    match next {
+                        Some((i, input)) => if sender.send((i, task(input))).is_err() { return; },
+                        None => return
+                    }
+                };
+                match poll_deadline {
+                    Some(d) => deadline::with_deadline(d, work),
+                    None => work()
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+    let mut results: Vec<Option<R>> = (0..count).map(|_| None).collect();
+    for (i, r) in receiver {
+        results[i] = Some(r);
+    }
+    for h in handles {
+        let _ = h.join();
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn returns_results_in_the_order_of_inputs() {
+        // earlier inputs finish later, so that results arrive out of order
+        let results = fan_out((0..8).collect(), 4, |i: u64| {
+            thread::sleep(Duration::from_millis(40 - i * 5));
+            i * 10
+        });
+        assert_eq!(results, (0..8).map(|i| Some(i * 10)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn panicking_task_yields_none() {
+        let results = fan_out(vec![1, 2, 3], 2, |i: u32| if i == 2 { panic!("task failed") } else { i });
+        assert_eq!(results, vec![Some(1), None, Some(3)]);
+    }
+
+    #[test]
+    fn empty_inputs_spawn_no_workers() {
+        let results: Vec<Option<u32>> = fan_out(Vec::new(), 4, |i: u32| i);
+        assert!(results.is_empty());
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::fs::File;
+use std::io::{stderr, Write, BufRead, BufReader};
+use std::str::FromStr;
+use serde_yaml;
+use prometheus::{Gauge, Collector};
+use rusoto::{Region, SignedRequest};
+use url::form_urlencoded;
+use config::{InstanceTypesSettingsProvider, AwsInstancesPollerSettingsProvider, InstanceTypeSettings, ConfigError};
+use poller::{Poller, CollectorGroup};
+use aws_poller::{AwsPollerError, PollerResult, instance_regions};
+use aws_connector::{HttpClientFactory, SharedHttpClient};
+use aws_request::{self, xml_element_text, xml_list_items};
+use credentials::CredentialsProviderWrapper;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct InstanceTypeInfo {
+    pub vcpus: u32,
+    pub memory_mib: u32,
+    /// Hourly on-demand price by platform (`linux`, `windows`)
+    pub on_demand_prices: HashMap<String, f64>
+}
+
+impl InstanceTypeInfo {
+    pub fn memory_bytes(&self) -> f64 {
+        self.memory_mib as f64 * 1024.0 * 1024.0
+    }
+
+    pub fn on_demand_price(&self, platform: &str) -> Option<f64> {
+        self.on_demand_prices.get(platform).cloned()
+    }
+}
+
+impl<'a> From<&'a InstanceTypeSettings> for InstanceTypeInfo {
+    fn from(settings: &'a InstanceTypeSettings) -> Self {
+        InstanceTypeInfo {
+            vcpus: settings.vcpus,
+            memory_mib: settings.memory_mib,
+            on_demand_prices: settings.on_demand_price.clone().unwrap_or(HashMap::new())
+        }
+    }
+}
+
+/// Known EC2 instance types: name, vCPUs, memory in MiB.
+/// Current rusoto EC2 model lacks `DescribeInstanceTypes`, so the catalogue is built in and may be
+/// refreshed with a raw `DescribeInstanceTypes` call, extended or overridden from the catalogue
+/// file and the configuration.
+const BUILTIN_INSTANCE_TYPES: &'static [(&'static str, u32, u32)] = &[
+    ("t1.micro", 1, 627),
+    ("t2.nano", 1, 512), ("t2.micro", 1, 1024), ("t2.small", 1, 2048), ("t2.medium", 2, 4096),
+    ("t2.large", 2, 8192), ("t2.xlarge", 4, 16384), ("t2.2xlarge", 8, 32768),
+    ("t3.nano", 2, 512), ("t3.micro", 2, 1024), ("t3.small", 2, 2048), ("t3.medium", 2, 4096),
+    ("t3.large", 2, 8192), ("t3.xlarge", 4, 16384), ("t3.2xlarge", 8, 32768),
+    ("m1.small", 1, 1741), ("m1.medium", 1, 3840), ("m1.large", 2, 7680), ("m1.xlarge", 4, 15360),
+    ("m2.xlarge", 2, 17510), ("m2.2xlarge", 4, 35021), ("m2.4xlarge", 8, 70042),
+    ("m3.medium", 1, 3840), ("m3.large", 2, 7680), ("m3.xlarge", 4, 15360), ("m3.2xlarge", 8, 30720),
+    ("m4.large", 2, 8192), ("m4.xlarge", 4, 16384), ("m4.2xlarge", 8, 32768), ("m4.4xlarge", 16, 65536),
+    ("m4.10xlarge", 40, 163840), ("m4.16xlarge", 64, 262144),
+    ("m5.large", 2, 8192), ("m5.xlarge", 4, 16384), ("m5.2xlarge", 8, 32768), ("m5.4xlarge", 16, 65536),
+    ("m5.8xlarge", 32, 131072), ("m5.12xlarge", 48, 196608), ("m5.16xlarge", 64, 262144),
+    ("m5.24xlarge", 96, 393216), ("m5.metal", 96, 393216),
+    ("c1.medium", 2, 1741), ("c1.xlarge", 8, 7168),
+    ("c3.large", 2, 3840), ("c3.xlarge", 4, 7680), ("c3.2xlarge", 8, 15360), ("c3.4xlarge", 16, 30720),
+    ("c3.8xlarge", 32, 61440),
+    ("c4.large", 2, 3840), ("c4.xlarge", 4, 7680), ("c4.2xlarge", 8, 15360), ("c4.4xlarge", 16, 30720),
+    ("c4.8xlarge", 36, 61440),
+    ("c5.large", 2, 4096), ("c5.xlarge", 4, 8192), ("c5.2xlarge", 8, 16384), ("c5.4xlarge", 16, 32768),
+    ("c5.9xlarge", 36, 73728), ("c5.12xlarge", 48, 98304), ("c5.18xlarge", 72, 147456),
+    ("c5.24xlarge", 96, 196608), ("c5.metal", 96, 196608),
+    ("r3.large", 2, 15616), ("r3.xlarge", 4, 31232), ("r3.2xlarge", 8, 62464), ("r3.4xlarge", 16, 124928),
+    ("r3.8xlarge", 32, 249856),
+    ("r4.large", 2, 15616), ("r4.xlarge", 4, 31232), ("r4.2xlarge", 8, 62464), ("r4.4xlarge", 16, 124928),
+    ("r4.8xlarge", 32, 249856), ("r4.16xlarge", 64, 499712),
+    ("r5.large", 2, 16384), ("r5.xlarge", 4, 32768), ("r5.2xlarge", 8, 65536), ("r5.4xlarge", 16, 131072),
+    ("r5.8xlarge", 32, 262144), ("r5.12xlarge", 48, 393216), ("r5.16xlarge", 64, 524288),
+    ("r5.24xlarge", 96, 786432), ("r5.metal", 96, 786432),
+    ("x1.16xlarge", 64, 999424), ("x1.32xlarge", 128, 1998848),
+    ("i2.xlarge", 4, 31232), ("i2.2xlarge", 8, 62464), ("i2.4xlarge", 16, 124928), ("i2.8xlarge", 32, 249856),
+    ("i3.large", 2, 15616), ("i3.xlarge", 4, 31232), ("i3.2xlarge", 8, 62464), ("i3.4xlarge", 16, 124928),
+    ("i3.8xlarge", 32, 249856), ("i3.16xlarge", 64, 499712),
+    ("d2.xlarge", 4, 31232), ("d2.2xlarge", 8, 62464), ("d2.4xlarge", 16, 124928), ("d2.8xlarge", 36, 249856),
+    ("g2.2xlarge", 8, 15360), ("g2.8xlarge", 32, 61440),
+    ("p2.xlarge", 4, 62464), ("p2.8xlarge", 32, 499712), ("p2.16xlarge", 64, 749568),
+];
+
+/// Cached catalogue of instance type properties shared between pollers.
+#[derive(Clone)]
+pub struct InstanceTypeCatalogue {
+    catalogue_file: Option<String>,
+    on_demand_prices_file: Option<String>,
+    overrides: HashMap<String, InstanceTypeSettings>,
+    described: Arc<RwLock<Vec<(String, u32, u32)>>>,
+    types: Arc<RwLock<HashMap<String, InstanceTypeInfo>>>
+}
+
+impl InstanceTypeCatalogue {
+    pub fn new(settings_provider: &InstanceTypesSettingsProvider) -> Result<InstanceTypeCatalogue, ConfigError> {
+        let settings = settings_provider.instance_types_settings();
+        let result = InstanceTypeCatalogue {
+            catalogue_file: settings.catalogue_file,
+            on_demand_prices_file: settings.on_demand_prices_file,
+            overrides: settings.instance_types.unwrap_or(HashMap::new()),
+            described: Arc::new(RwLock::new(Vec::new())),
+            types: Arc::new(RwLock::new(HashMap::new()))
+        };
+        result.reload()?;
+        Ok(result)
+    }
+
+    /// Rebuilds the catalogue from the built in table, the types described by AWS, the catalogue
+    /// file and the configuration overrides, in the order of precedence. Prices from the on-demand
+    /// prices file take precedence over all of them.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let described = self.described.read().unwrap();
+        let mut types: HashMap<String, InstanceTypeInfo> = BUILTIN_INSTANCE_TYPES.iter()
+            .map(|&(name, vcpus, memory_mib)| (name, vcpus, memory_mib))
+            .chain(described.iter().map(|&(ref name, vcpus, memory_mib)| (name.as_str(), vcpus, memory_mib)))
+            .map(|(name, vcpus, memory_mib)| (name.to_owned(), InstanceTypeInfo {
+                vcpus: vcpus,
+                memory_mib: memory_mib,
+                on_demand_prices: HashMap::new()
+            }))
+            .collect();
+        if let Some(ref filename) = self.catalogue_file {
+            let from_file: HashMap<String, InstanceTypeSettings> = serde_yaml::from_reader(File::open(filename)?)?;
+            for (name, settings) in from_file.iter() {
+                types.insert(name.clone(), InstanceTypeInfo::from(settings));
+            }
+        }
+        for (name, settings) in self.overrides.iter() {
+            types.insert(name.clone(), InstanceTypeInfo::from(settings));
+        }
+        if let Some(ref filename) = self.on_demand_prices_file {
+            for (instance_type, platform, price) in Self::read_on_demand_prices(filename)? {
+                match types.get_mut(&instance_type) {
+                    Some(info) => { info.on_demand_prices.insert(platform, price); }
+                    None => {
+                        let _ = writeln!(&mut stderr(), "Price for unknown instance type {:?} is ignored",
+                                         instance_type);
+                    }
+                }
+            }
+        }
+        *self.types.write().unwrap() = types;
+        Ok(())
+    }
+
+    /// Reads `type,platform,price` lines of the CSV file, header and `#` comments are skipped.
+    fn read_on_demand_prices(filename: &str) -> Result<Vec<(String, String, f64)>, ConfigError> {
+        let mut result = Vec::new();
+        for (n, line) in BufReader::new(File::open(filename)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("type,") {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            match (fields.len(), fields.get(2).and_then(|p| p.parse::<f64>().ok())) {
+                (3, Some(price)) => result.push((fields[0].to_owned(), fields[1].to_owned(), price)),
+                _ => return Err(ConfigError::SyntaxError(
+                    format!("{}:{}: expected type,platform,price", filename, n + 1)))
+            }
+        }
+        Ok(result)
+    }
+
+    /// Replaces instance types returned by `DescribeInstanceTypes`, takes effect on `reload`.
+    fn set_described(&self, types: Vec<(String, u32, u32)>) {
+        *self.described.write().unwrap() = types;
+    }
+
+    pub fn get(&self, instance_type: &str) -> Option<InstanceTypeInfo> {
+        self.types.read().unwrap().get(instance_type).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.types.read().unwrap().len()
+    }
+}
+
+/// Returns name, vCPUs and memory in MiB of instance types in `DescribeInstanceTypes` response.
+fn parse_instance_types(document: &str) -> Vec<(String, u32, u32)> {
+    xml_list_items(document, "instanceTypeSet").into_iter()
+        .filter_map(|item| {
+            let name = xml_element_text(item, "instanceType");
+            let vcpus = xml_element_text(item, "defaultVCpus").and_then(|v| v.parse::<u32>().ok());
+            // sizeInMiB elements of other infos, e.g. GPU memory, may precede the memory info
+            let memory_mib = item.find("<memoryInfo>")
+                .and_then(|start| xml_element_text(&item[start..], "sizeInMiB"))
+                .and_then(|m| m.parse::<u32>().ok());
+            match (name, vcpus, memory_mib) {
+                (Some(name), Some(vcpus), Some(memory_mib)) => Some((name.to_owned(), vcpus, memory_mib)),
+                _ => None
+            }
+        })
+        .collect()
+}
+
+/// Lists instance types offered in the region with `DescribeInstanceTypes`.
+struct InstanceTypesQuery {
+    credentials_provider: CredentialsProviderWrapper,
+    region: Region,
+    http_client: SharedHttpClient
+}
+
+impl InstanceTypesQuery {
+    fn describe_instance_types(&self) -> PollerResult<Vec<(String, u32, u32)>> {
+        let mut result = Vec::new();
+        let mut next_token: Option<String> = None;
+        loop {
+            let mut body = form_urlencoded::Serializer::new(String::new());
+            body.append_pair("Action", "DescribeInstanceTypes").append_pair("Version", "2016-11-15");
+            if let Some(ref token) = next_token {
+                body.append_pair("NextToken", token);
+            }
+            let mut request = SignedRequest::new("POST", "ec2", self.region, "/");
+            request.set_content_type("application/x-www-form-urlencoded".to_owned());
+            request.set_payload(Some(body.finish().into_bytes()));
+            let document = match aws_request::send_signed(&self.http_client, &self.credentials_provider, request)? {
+                Ok(d) => d,
+                Err(e) => return Err(e.into_poller_error("DescribeInstanceTypes"))
+            };
+            result.extend(parse_instance_types(&document));
+            next_token = match xml_element_text(&document, "nextToken") {
+                Some(t) if !t.is_empty() => Some(t.to_owned()),
+                _ => return Ok(result)
+            };
+        }
+    }
+}
+
+/// Periodically refreshes the instance type catalogue.
+pub struct InstanceTypeCataloguePoller {
+    catalogue: InstanceTypeCatalogue,
+    query: InstanceTypesQuery,
+    size: Gauge
+}
+
+impl InstanceTypeCataloguePoller {
+    /// Instance types are described in the region of the instance types settings, or with the
+    /// region, credentials and endpoint of the instances poller if the region is not set.
+    pub fn new<S>(catalogue: InstanceTypeCatalogue, settings_provider: &S,
+                  http_clients: &HttpClientFactory) -> PollerResult<InstanceTypeCataloguePoller>
+        where S: InstanceTypesSettingsProvider + AwsInstancesPollerSettingsProvider
+    {
+        let settings = settings_provider.instance_types_settings();
+        let (region, provider_type, profile_name, credentials_file, endpoint_url) = match settings.region {
+            Some(region) => (Region::from_str(&region)?, settings.credentials_provider, settings.profile_name,
+                             settings.credentials_file, settings.endpoint_url),
+            None => {
+                let instances = settings_provider.aws_instances_poller_settings();
+                (instance_regions(&instances)?[0], instances.credentials_provider, instances.profile_name,
+                 instances.credentials_file, instances.endpoint_url)
+            }
+        };
+        let credentials_provider = CredentialsProviderWrapper::from_type(
+            "instance_types", provider_type, profile_name, credentials_file, region, http_clients)?;
+        let http_client = http_clients.client(endpoint_url.as_ref().map(|s| &**s), &credentials_provider, region)?;
+        let query = InstanceTypesQuery {
+            credentials_provider: credentials_provider,
+            region: region,
+            http_client: http_client
+        };
+        let size = Gauge::new("deucalion_instance_type_catalogue_size", "Number of known instance types")?;
+        size.set(catalogue.len() as f64);
+        Ok(InstanceTypeCataloguePoller {
+            catalogue: catalogue,
+            query: query,
+            size: size
+        })
+    }
+}
+
+impl Poller for InstanceTypeCataloguePoller {
+    fn name(&self) -> &str {
+        "instance_types"
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let mut result = Ok(());
+        // the previously described types are kept if AWS is not available
+        match self.query.describe_instance_types() {
+            Ok(types) => self.catalogue.set_described(types),
+            Err(e) => {
+                let _ = writeln!(&mut stderr(), "Could not describe instance types: {:?}", e);
+                self.query.credentials_provider.revalidate_on(&e);
+                result = Err(e);
+            }
+        }
+        match self.catalogue.reload() {
+            Ok(_) => {
+                self.size.set(self.catalogue.len() as f64);
+                result
+            }
+            Err(e) => {
+                let _ = writeln!(&mut stderr(), "Could not reload instance types catalogue: {:?}", e);
+                Err(AwsPollerError::UnknownError(format!("{:?}", e)))
+            }
+        }
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(vec![
+            Box::new(self.size.clone()),
+            self.query.credentials_provider.counters()
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_described_instance_types() {
+        let document = "<DescribeInstanceTypesResponse><instanceTypeSet>\
+            <item><instanceType>m5.large</instanceType><vCpuInfo><defaultVCpus>2</defaultVCpus></vCpuInfo>\
+            <memoryInfo><sizeInMiB>8192</sizeInMiB></memoryInfo></item>\
+            <item><instanceType>broken.large</instanceType></item>\
+            <item><instanceType>c5.xlarge</instanceType><vCpuInfo><defaultVCpus>4</defaultVCpus></vCpuInfo>\
+            <memoryInfo><sizeInMiB>8192</sizeInMiB></memoryInfo></item>\
+            </instanceTypeSet><nextToken>abc</nextToken></DescribeInstanceTypesResponse>";
+        assert_eq!(parse_instance_types(document), vec![
+            ("m5.large".to_owned(), 2, 8192),
+            ("c5.xlarge".to_owned(), 4, 8192),
+        ]);
+    }
+}
@@ -3,9 +3,15 @@ extern crate hyper;
 extern crate dotenv;
 extern crate rusoto;
 extern crate ctrlc;
+extern crate libc;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_yaml;
+extern crate serde_json;
+extern crate chrono;
+extern crate url;
+extern crate hyper_native_tls;
 extern crate time;
 extern crate env_logger;
 
@@ -15,16 +21,36 @@ mod periodic;
 mod server;
 mod termination;
 mod pagination;
+mod instance_types;
+mod credentials;
+mod aws_connector;
+mod aws_request;
+mod deadline;
+mod rate_limiter;
+mod fan_out;
+#[macro_use]
 mod aws_poller;
+mod aws_autoscaling_poller;
+mod aws_cloudwatch_poller;
+mod aws_elb_poller;
+mod aws_rds_poller;
+mod pollers;
+mod reload;
+
+const CONFIG_FILENAME: &'static str = "config.yml";
 
 use std::time::Duration;
+use std::thread;
 use hyper::server::Server;
-use config::{ScrapeSettingsProvider};
+use config::{ScrapeSettingsProvider, InstanceTypesSettingsProvider, HttpClientSettingsProvider};
 use server::DeucalionHandler;
-use poller::Poller;
-use aws_poller::{AwsInstancesPoller, AwsSpotPricesPoller};
-use periodic::AsyncPeriodicRunner;
+use aws_connector::HttpClientFactory;
+use poller::{Poller, Readiness};
+use pollers::{PollerBuilder, POLLERS};
+use periodic::{AsyncPeriodicRunner, Scheduler};
+use reload::ConfigReloader;
 use termination::TerminationGuard;
+use instance_types::{InstanceTypeCatalogue, InstanceTypeCataloguePoller};
 use prometheus::{TextEncoder, Registry};
 
 fn inject_environment() {
@@ -41,26 +67,62 @@ fn main() {
     inject_environment();
     env_logger::init().unwrap();
 
-    let config = config::DeucalionSettings::from_filename("config.yml")
+    let config = config::DeucalionSettings::from_filename(CONFIG_FILENAME)
         .expect("Could not load configuration");
     let polling_period = config.polling_period()
         .unwrap_or(Duration::from_secs(60));
-    let aws_instances_poller = AwsInstancesPoller::new(&config)
-        .expect("Could not initialize AWS Instances poller");
-    let aws_spot_prices_poller = AwsSpotPricesPoller::new(&config)
-        .expect("Could not initialize AWS Spot Prices poller");
+    let poll_deadline = config.poll_deadline()
+        .unwrap_or(polling_period);
+    // Prometheus gives up on a scrape after 10 seconds by default
+    let scrape_deadline = config.scrape_deadline()
+        .unwrap_or(Duration::from_secs(8));
+    let instance_types = InstanceTypeCatalogue::new(&config)
+        .expect("Could not load instance types catalogue");
+    let instance_types_refresh_period = config.instance_types_settings().refresh_period
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(3600));
+    let degraded_startup = config.degraded_startup();
+    let shutdown_grace_period = config.shutdown_grace_period()
+        .unwrap_or(Duration::from_secs(config::DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS));
+    let shutdown_timeout = config.shutdown_timeout()
+        .unwrap_or(Duration::from_secs(config::DEFAULT_SHUTDOWN_TIMEOUT_SECS));
+    let readiness = Readiness::new();
+    let http_clients = HttpClientFactory::new(config.http_client_settings())
+        .expect("Could not initialize HTTP clients");
+    let instance_types_poller = InstanceTypeCataloguePoller::new(instance_types.clone(), &config, &http_clients)
+        .expect("Could not initialize instance types poller");
+    let builder = PollerBuilder::new(http_clients, instance_types, readiness.clone(), degraded_startup);
+    let aws_pollers: Vec<_> = POLLERS.iter()
+        .filter_map(|&name| builder.build(name, &config)
+            .unwrap_or_else(|e| panic!("Could not initialize AWS {} poller: {:?}", name, e)))
+        .collect();
 
     let registry = Registry::new();
-    registry.register(aws_instances_poller.counters()).unwrap();
-    registry.register(aws_spot_prices_poller.counters()).unwrap();
+    registry.register(instance_types_poller.counters()).unwrap();
 
+    let scheduler = Scheduler::new(polling_period, poll_deadline, scrape_deadline, registry.clone());
+    let _instance_types_runner = AsyncPeriodicRunner::new(instance_types_poller, instance_types_refresh_period);
+    for p in aws_pollers {
+        let settings = config.poller_schedule(p.name());
+        scheduler.schedule(p, settings);
+    }
+    let reloader = ConfigReloader::new(CONFIG_FILENAME, &config, builder, scheduler.clone(), readiness.clone())
+        .expect("Could not initialize configuration reloader");
+    registry.register(reloader.counters()).unwrap();
+    reloader.watch();
     let mut listening = Server::http(config.listen_on())
         .unwrap()
-        .handle(DeucalionHandler::new(TextEncoder::new(), registry))
+        .handle(DeucalionHandler::new(TextEncoder::new(), registry, readiness.clone(), scheduler.clone(),
+                                      config.refresh_token()))
         .unwrap();
-    let _aws_instances_runner = AsyncPeriodicRunner::new(aws_instances_poller, polling_period.clone());
-    let _aws_spot_prices_runner = AsyncPeriodicRunner::new(aws_spot_prices_poller, polling_period.clone());
     TerminationGuard::new();
 
+    termination::exit_after(shutdown_timeout);
+    readiness.shut_down();
+    thread::sleep(shutdown_grace_period);
+    readiness.stop();
+    // does not stop the listener, only keeps the drop from waiting for it
     let _ = listening.close();
+    deadline::cancel_all();
+    scheduler.stop();
 }
@@ -2,11 +2,22 @@
 use std::time::{Duration, Instant};
 use std::thread;
 use std::sync::{Arc, Mutex, Condvar};
-use poller::Poller;
+use std::io::{stderr, Write};
+use std::mem;
+use prometheus::Registry;
+use poller::{Poller, SharedCollector};
+use config::{PollerMode, PollerScheduleSettings};
+use fan_out::fan_out;
+use deadline;
+
+struct RunnerState {
+    terminate: bool,
+    poll_now: bool
+}
 
 pub struct AsyncPeriodicRunner
 {
-    terminate: Arc<(Mutex<bool>, Condvar)>,
+    state: Arc<(Mutex<RunnerState>, Condvar)>,
     thread: Option<thread::JoinHandle<()>>
 }
 
@@ -14,16 +25,33 @@ impl AsyncPeriodicRunner
 {
     pub fn new<P: Poller + 'static>(poller: P, poll_period: Duration) -> AsyncPeriodicRunner
     {
-        let terminate_arc = Arc::new((Mutex::new(false), Condvar::new()));
+        Self::with_deadline(poller, poll_period, poll_period)
+    }
+
+    /// Every poll is abandoned after `poll_deadline`, see `deadline::with_deadline`.
+    pub fn with_deadline<P: Poller + 'static>(poller: P, poll_period: Duration, poll_deadline: Duration)
+                                              -> AsyncPeriodicRunner
+    {
+        let state_arc = Arc::new((Mutex::new(RunnerState { terminate: false, poll_now: false }), Condvar::new()));
         let result = AsyncPeriodicRunner{
-            terminate: terminate_arc.clone(),
+            state: state_arc.clone(),
             thread: Some(thread::spawn(move || -> () {
-                let &(ref l, ref cvar) = &*terminate_arc;
-                let mut terminate = l.lock().unwrap();
-                while !*terminate {
+                let &(ref l, ref cvar) = &*state_arc;
+                loop {
+                    {
+                        let mut state = l.lock().unwrap();
+                        if state.terminate {
+                            break;
+                        }
+                        state.poll_now = false;
+                    }
                     let now = Instant::now();
-                    poller.poll();
+                    let _ = deadline::with_deadline(now + poll_deadline, || poller.poll());
                     let elapsed = now.elapsed();
+                    if elapsed >= poll_deadline {
+                        let _ = writeln!(&mut stderr(), "Poll hit the deadline of {:?}, previous metrics are kept",
+                                         poll_deadline);
+                    }
                     let sleep_duration = if elapsed < poll_period {
                         poll_period - elapsed
                     } else {
@@ -31,22 +59,41 @@ impl AsyncPeriodicRunner
                                  elapsed);
                         Duration::from_secs(0)
                     };
-                    terminate = cvar.wait_timeout(terminate, sleep_duration).unwrap().0;
+                    let wake_up = Instant::now() + sleep_duration;
+                    let mut state = l.lock().unwrap();
+                    // protection against spurious wakes
+                    while !state.terminate && !state.poll_now {
+                        let now = Instant::now();
+                        if now >= wake_up {
+                            break;
+                        }
+                        state = cvar.wait_timeout(state, wake_up - now).unwrap().0;
+                    }
                 }
             }))
         };
         return result;
     }
+
+    /// Wakes the runner up to poll right away. Requests made while a poll is running are
+    /// coalesced into a single poll which starts once the running one is over.
+    pub fn poll_now(&self)
+    {
+        let &(ref l, ref cvar) = &*self.state;
+        let mut state = l.lock().unwrap();
+        state.poll_now = true;
+        cvar.notify_one();
+    }
 }
 
 impl Drop for AsyncPeriodicRunner
 {
     fn drop(&mut self)
     {
-        let &(ref l, ref cvar) = &*self.terminate;
+        let &(ref l, ref cvar) = &*self.state;
         {
-            let mut terminate = l.lock().unwrap();
-            *terminate = true;
+            let mut state = l.lock().unwrap();
+            state.terminate = true;
             cvar.notify_one();
         }
         println!("Waiting for poller thread to exit...");
@@ -55,3 +102,275 @@ impl Drop for AsyncPeriodicRunner
         };
     }
 }
+
+struct OnScrapeState {
+    polled: Option<Instant>,
+    in_flight: bool
+}
+
+/// Polls when metrics are scraped instead of in the background. Results are reused for `cache_ttl`
+/// and concurrent scrapes wait for the poll which is already in flight instead of starting another.
+pub struct OnScrapeRunner
+{
+    poller: Box<Poller>,
+    cache_ttl: Duration,
+    scrape_deadline: Duration,
+    state: Mutex<OnScrapeState>,
+    done: Condvar
+}
+
+impl OnScrapeRunner
+{
+    /// Polls are abandoned after `scrape_deadline`, so that the scrape is answered before
+    /// Prometheus gives up on it.
+    pub fn new<P: Poller + 'static>(poller: P, cache_ttl: Duration, scrape_deadline: Duration) -> OnScrapeRunner
+    {
+        OnScrapeRunner {
+            poller: Box::new(poller),
+            cache_ttl: cache_ttl,
+            scrape_deadline: scrape_deadline,
+            state: Mutex::new(OnScrapeState {
+                polled: None,
+                in_flight: false
+            }),
+            done: Condvar::new()
+        }
+    }
+
+    /// Makes the next scrape poll regardless of the cache.
+    pub fn expire(&self)
+    {
+        self.state.lock().unwrap().polled = None;
+    }
+
+    pub fn refresh(&self)
+    {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.in_flight {
+                while state.in_flight {
+                    state = self.done.wait(state).unwrap();
+                }
+                return;
+            }
+            if state.polled.map_or(false, |p| p.elapsed() < self.cache_ttl) {
+                return;
+            }
+            state.in_flight = true;
+        }
+        // waiting scrapes are released even if the poll panics
+        let _in_flight = InFlightGuard { runner: self };
+        let now = Instant::now();
+        let _ = deadline::with_deadline(now + self.scrape_deadline, || self.poller.poll());
+        if now.elapsed() >= self.scrape_deadline {
+            let _ = writeln!(&mut stderr(), "Poll hit the scrape deadline of {:?}, previous metrics are kept",
+                             self.scrape_deadline);
+        }
+    }
+}
+
+struct InFlightGuard<'a> {
+    runner: &'a OnScrapeRunner
+}
+
+impl<'a> Drop for InFlightGuard<'a> {
+    fn drop(&mut self)
+    {
+        let mut state = match self.runner.state.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        state.polled = Some(Instant::now());
+        state.in_flight = false;
+        self.runner.done.notify_all();
+    }
+}
+
+enum Runner {
+    Background(AsyncPeriodicRunner),
+    OnScrape(Arc<OnScrapeRunner>)
+}
+
+struct Scheduled {
+    name: String,
+    runner: Runner,
+    counters: SharedCollector
+}
+
+/// Runs pollers in the background or on scrape according to their mode, and lets them be polled
+/// on demand by name. Metrics of pollers are registered while they are scheduled.
+#[derive(Clone)]
+pub struct Scheduler
+{
+    polling_period: Duration,
+    poll_deadline: Duration,
+    scrape_deadline: Duration,
+    registry: Registry,
+    runners: Arc<Mutex<Vec<Scheduled>>>
+}
+
+impl Scheduler
+{
+    /// Background polls are abandoned after `poll_deadline`, polls in `on_scrape` mode after
+    /// `scrape_deadline`.
+    pub fn new(polling_period: Duration, poll_deadline: Duration, scrape_deadline: Duration, registry: Registry)
+               -> Scheduler
+    {
+        Scheduler {
+            polling_period: polling_period,
+            poll_deadline: poll_deadline,
+            scrape_deadline: scrape_deadline,
+            registry: registry,
+            runners: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    /// Replaces the poller with the same name if it is already scheduled.
+    pub fn schedule<P: Poller + 'static>(&self, poller: P, settings: PollerScheduleSettings)
+    {
+        let name = poller.name().to_owned();
+        self.remove(&name);
+        let counters = SharedCollector::new(poller.counters());
+        if let Err(e) = self.registry.register(Box::new(counters.clone())) {
+            let _ = writeln!(&mut stderr(), "Could not register metrics of {} poller: {:?}", name, e);
+        }
+        let runner = match settings.mode.unwrap_or(PollerMode::Background) {
+            PollerMode::Background =>
+                Runner::Background(AsyncPeriodicRunner::with_deadline(poller, self.polling_period,
+                                                                      self.poll_deadline)),
+            PollerMode::OnScrape => {
+                let cache_ttl = settings.cache_ttl.map(Duration::from_secs).unwrap_or(self.polling_period);
+                Runner::OnScrape(Arc::new(OnScrapeRunner::new(poller, cache_ttl, self.scrape_deadline)))
+            }
+        };
+        self.runners.lock().unwrap().push(Scheduled {
+            name: name,
+            runner: runner,
+            counters: counters
+        });
+    }
+
+    /// Stops the named poller and unregisters its metrics, waiting for its running poll to finish.
+    pub fn remove(&self, name: &str)
+    {
+        let removed: Vec<Scheduled> = {
+            let mut runners = self.runners.lock().unwrap();
+            let (removed, kept) = mem::replace(&mut *runners, Vec::new()).into_iter()
+                .partition(|s| s.name == name);
+            *runners = kept;
+            removed
+        };
+        for s in removed {
+            let _ = self.registry.unregister(Box::new(s.counters.clone()));
+        }
+    }
+
+    /// Polls pollers in `on_scrape` mode in parallel, unless their results are cached.
+    pub fn poll_on_scrape(&self)
+    {
+        let on_scrape: Vec<_> = self.runners.lock().unwrap().iter()
+            .filter_map(|s| match s.runner {
+                Runner::OnScrape(ref r) => Some(r.clone()),
+                Runner::Background(_) => None
+            })
+            .collect();
+        if !on_scrape.is_empty() {
+            let workers = on_scrape.len();
+            fan_out(on_scrape, workers, |r| r.refresh());
+        }
+    }
+
+    /// Wakes up the named poller, pollers in `on_scrape` mode poll on the next scrape.
+    /// Returns `false` if there is no such poller.
+    pub fn poll_now(&self, name: &str) -> bool
+    {
+        match self.runners.lock().unwrap().iter().find(|s| s.name == name).map(|s| &s.runner) {
+            Some(&Runner::Background(ref r)) => { r.poll_now(); true }
+            Some(&Runner::OnScrape(ref r)) => { r.expire(); true }
+            None => false
+        }
+    }
+
+    /// Stops background runners, waiting for running polls to finish.
+    pub fn stop(&self)
+    {
+        let runners = mem::replace(&mut *self.runners.lock().unwrap(), Vec::new());
+        drop(runners);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use prometheus::Collector;
+    use poller::CollectorGroup;
+    use aws_poller::PollerResult;
+
+    struct SlowPoller {
+        polls: Arc<AtomicUsize>,
+        panics: bool
+    }
+
+    impl Poller for SlowPoller {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        fn poll(&self) -> PollerResult<()> {
+            self.polls.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            if self.panics {
+                panic!("poll failed");
+            }
+            Ok(())
+        }
+
+        fn counters(&self) -> Box<Collector> {
+            Box::new(CollectorGroup::new(Vec::new()))
+        }
+    }
+
+    fn on_scrape_runner(panics: bool) -> (Arc<OnScrapeRunner>, Arc<AtomicUsize>) {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let poller = SlowPoller { polls: polls.clone(), panics: panics };
+        (Arc::new(OnScrapeRunner::new(poller, Duration::from_secs(60), Duration::from_secs(5))), polls)
+    }
+
+    #[test]
+    fn concurrent_scrapes_share_one_poll() {
+        let (runner, polls) = on_scrape_runner(false);
+        let scrapes: Vec<_> = (0..4)
+            .map(|_| {
+                let runner = runner.clone();
+                thread::spawn(move || runner.refresh())
+            })
+            .collect();
+        for s in scrapes {
+            s.join().unwrap();
+        }
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+        runner.refresh();
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+        runner.expire();
+        runner.refresh();
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn panicking_poll_releases_waiting_scrapes() {
+        let (runner, polls) = on_scrape_runner(true);
+        let polling = {
+            let runner = runner.clone();
+            thread::spawn(move || runner.refresh())
+        };
+        thread::sleep(Duration::from_millis(20));
+        // waits for the poll in flight and returns once it panics
+        runner.refresh();
+        assert!(polling.join().is_err());
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+        runner.expire();
+        assert!(thread::spawn(move || runner.refresh()).join().is_err());
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,6 +1,443 @@
-use prometheus::Collector;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{stderr, Write};
+use std::time::{Duration, Instant};
+use prometheus::{Collector, Desc, Opts, Gauge, CounterVec};
+use prometheus::proto::{MetricFamily, LabelPair};
+use config::{MaxStalenessSettings, StaleSeriesAction};
+use aws_poller::{AwsPollerError, PollerResult};
 
 pub trait Poller: Sync + Send {
-    fn poll(&self);
+    /// Short name of the poller used in metric labels and logs.
+    fn name(&self) -> &str;
+
+    /// Verifies that the poller is able to poll, e.g. credentials are valid and permissions
+    /// are granted.
+    fn check(&self) -> Option<AwsPollerError> {
+        None
+    }
+
+    /// Errors are already reported by the poller, the result is used for accounting only.
+    fn poll(&self) -> PollerResult<()>;
     fn counters(&self) -> Box<Collector>;
 }
+
+impl Poller for Box<Poller> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        (**self).check()
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        (**self).poll()
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        (**self).counters()
+    }
+}
+
+/// Exposes several collectors of a single poller as one collector.
+pub struct CollectorGroup {
+    collectors: Vec<Box<Collector>>
+}
+
+impl CollectorGroup {
+    pub fn new(collectors: Vec<Box<Collector>>) -> CollectorGroup {
+        CollectorGroup {
+            collectors: collectors
+        }
+    }
+}
+
+impl Collector for CollectorGroup {
+    fn desc(&self) -> Vec<&Desc> {
+        self.collectors.iter().flat_map(|c| c.desc()).collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.collectors.iter().flat_map(|c| c.collect()).collect()
+    }
+}
+
+/// Collector which is shared with the registry, so that it can be unregistered later.
+#[derive(Clone)]
+pub struct SharedCollector {
+    inner: Arc<Box<Collector>>
+}
+
+impl SharedCollector {
+    pub fn new(inner: Box<Collector>) -> SharedCollector {
+        SharedCollector {
+            inner: Arc::new(inner)
+        }
+    }
+}
+
+impl Collector for SharedCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.inner.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.inner.collect()
+    }
+}
+
+/// Exposes metrics published by the last successful poll. A poll fills its own collectors off
+/// to the side and publishes them at once, so that a scrape never sees a half-updated state.
+/// Series of a failing poller are dropped or marked with `stale="true"` label once the snapshot
+/// exceeds `max_staleness`. Until the first successful poll the data age is counted from startup.
+#[derive(Clone)]
+pub struct SnapshotCollector {
+    template: Arc<Box<Collector>>,
+    snapshot: Arc<RwLock<Snapshot>>,
+    max_staleness: Option<MaxStalenessSettings>,
+    started: Instant,
+    data_age: Gauge
+}
+
+struct Snapshot {
+    families: Vec<MetricFamily>,
+    published: Option<Instant>,
+    failed_polls: u32
+}
+
+impl SnapshotCollector {
+    /// `template` provides descriptors of the published metrics, it is never collected.
+    pub fn new(poller_name: &str, template: Box<Collector>, max_staleness: Option<MaxStalenessSettings>)
+               -> PollerResult<SnapshotCollector> {
+        Ok(SnapshotCollector {
+            template: Arc::new(template),
+            snapshot: Arc::new(RwLock::new(Snapshot {
+                families: Vec::new(),
+                published: None,
+                failed_polls: 0
+            })),
+            max_staleness: max_staleness,
+            started: Instant::now(),
+            data_age: Gauge::with_opts(
+                Opts::new("deucalion_data_age_seconds", "Time since the last successful poll")
+                    .const_label("poller", poller_name))?
+        })
+    }
+
+    pub fn publish(&self, collector: &Collector) {
+        let families = collector.collect();
+        let mut snapshot = self.snapshot.write().unwrap();
+        snapshot.families = families;
+        snapshot.published = Some(Instant::now());
+        snapshot.failed_polls = 0;
+    }
+
+    /// Records a failed poll, the previous snapshot is kept until it gets too stale.
+    pub fn fail(&self) {
+        self.snapshot.write().unwrap().failed_polls += 1;
+    }
+
+    fn is_stale(&self, snapshot: &Snapshot, age: Duration) -> bool {
+        match self.max_staleness {
+            Some(ref s) => s.failed_polls.map_or(false, |n| snapshot.failed_polls >= n)
+                || s.seconds.map_or(false, |t| age >= Duration::from_secs(t)),
+            None => false
+        }
+    }
+}
+
+impl Collector for SnapshotCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = self.template.desc();
+        descs.extend(self.data_age.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let snapshot = self.snapshot.read().unwrap();
+        let age = snapshot.published.unwrap_or(self.started).elapsed();
+        self.data_age.set(age.as_secs() as f64 + age.subsec_nanos() as f64 * 1e-9);
+        if snapshot.published.is_none() {
+            return self.data_age.collect();
+        }
+        let mut families = if !self.is_stale(&snapshot, age) {
+            snapshot.families.clone()
+        } else {
+            match self.max_staleness.as_ref().and_then(|s| s.action).unwrap_or(StaleSeriesAction::Drop) {
+                StaleSeriesAction::Drop => Vec::new(),
+                StaleSeriesAction::Mark => {
+                    let mut families = snapshot.families.clone();
+                    for m in families.iter_mut().flat_map(|f| f.mut_metric().iter_mut()) {
+                        let mut label = LabelPair::new();
+                        label.set_name("stale".to_owned());
+                        label.set_value("true".to_owned());
+                        m.mut_label().push(label);
+                    }
+                    families
+                }
+            }
+        };
+        families.extend(self.data_age.collect());
+        families
+    }
+}
+
+/// Readiness flags of all checked pollers.
+#[derive(Clone)]
+pub struct Readiness {
+    pollers: Arc<Mutex<Vec<(String, Arc<AtomicBool>)>>>,
+    shutting_down: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>
+}
+
+impl Readiness {
+    pub fn new() -> Readiness {
+        Readiness {
+            pollers: Arc::new(Mutex::new(Vec::new())),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    /// Reports the service as not ready for good, so that it is taken out of rotation.
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Stops serving metrics once the shutdown grace period is over, the listener itself cannot
+    /// be closed.
+    pub fn stop(&self) {
+        self.shut_down();
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Replaces the flag of a rebuilt poller.
+    fn add(&self, name: &str, ready: Arc<AtomicBool>) {
+        let mut pollers = self.pollers.lock().unwrap();
+        pollers.retain(|&(ref n, _)| n != name);
+        pollers.push((name.to_owned(), ready));
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.pollers.lock().unwrap().retain(|&(ref n, _)| n != name);
+    }
+
+    /// Flags of the current pollers, to be restored if rebuilt pollers are discarded.
+    pub fn save(&self) -> Vec<(String, Arc<AtomicBool>)> {
+        self.pollers.lock().unwrap().clone()
+    }
+
+    pub fn restore(&self, pollers: Vec<(String, Arc<AtomicBool>)>) {
+        *self.pollers.lock().unwrap() = pollers;
+    }
+
+    /// Returns names of pollers which have not passed their check yet.
+    pub fn degraded_pollers(&self) -> Vec<String> {
+        self.pollers.lock().unwrap().iter()
+            .filter(|&&(_, ref ready)| !ready.load(Ordering::SeqCst))
+            .map(|&(ref name, _)| name.clone())
+            .collect()
+    }
+}
+
+/// Polls only after the poller passed its check. The check is retried on every polling period
+/// while the poller is degraded.
+pub struct CheckedPoller<P: Poller> {
+    inner: P,
+    ready: Arc<AtomicBool>,
+    status: Gauge,
+    errors: CounterVec
+}
+
+impl<P: Poller> CheckedPoller<P> {
+    /// Fails if the check does not pass. With `degraded_startup` the check is deferred to the
+    /// first poll instead, so that startup does not wait for AWS.
+    pub fn new(inner: P, readiness: &Readiness, degraded_startup: bool) -> PollerResult<CheckedPoller<P>> {
+        let status = Gauge::with_opts(
+            Opts::new("deucalion_poller_ready", "Whether the poller passed its startup check")
+                .const_label("poller", inner.name()))?;
+        let ready = !degraded_startup;
+        if ready {
+            if let Some(e) = inner.check() {
+                return Err(e);
+            }
+        }
+        status.set(if ready { 1.0 } else { 0.0 });
+        let errors = CounterVec::new(
+            Opts::new("deucalion_poll_errors_total", "Number of failed polls by error kind")
+                .const_label("poller", inner.name()),
+            &["kind"])?;
+        let ready = Arc::new(AtomicBool::new(ready));
+        readiness.add(inner.name(), ready.clone());
+        Ok(CheckedPoller {
+            inner: inner,
+            ready: ready,
+            status: status,
+            errors: errors
+        })
+    }
+
+    /// Same as `new` for pollers which may be not configured.
+    pub fn new_optional(inner: PollerResult<Option<P>>, readiness: &Readiness, degraded_startup: bool)
+                        -> PollerResult<Option<CheckedPoller<P>>> {
+        match inner? {
+            Some(p) => Ok(Some(Self::new(p, readiness, degraded_startup)?)),
+            None => Ok(None)
+        }
+    }
+}
+
+impl<P: Poller> Poller for CheckedPoller<P> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn check(&self) -> Option<AwsPollerError> {
+        self.inner.check()
+    }
+
+    fn poll(&self) -> PollerResult<()> {
+        let result = if self.ready.load(Ordering::SeqCst) {
+            self.inner.poll()
+        } else if let Some(e) = self.inner.check() {
+            let _ = writeln!(&mut stderr(), "{} poller is degraded: {:?}", self.name(), e);
+            Err(e)
+        } else {
+            println!("{} poller is ready", self.name());
+            self.status.set(1.0);
+            self.ready.store(true, Ordering::SeqCst);
+            self.inner.poll()
+        };
+        if let Err(ref e) = result {
+            if let Ok(c) = self.errors.get_metric_with_label_values(&[e.kind()]) {
+                c.inc();
+            }
+        }
+        result
+    }
+
+    fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(vec![
+            self.inner.counters(),
+            Box::new(self.status.clone()),
+            Box::new(self.errors.clone()),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use prometheus::GaugeVec;
+
+    struct FakePoller {
+        healthy: Arc<AtomicBool>,
+        polls: Arc<AtomicUsize>
+    }
+
+    impl Poller for FakePoller {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn check(&self) -> Option<AwsPollerError> {
+            if self.healthy.load(Ordering::SeqCst) {
+                None
+            } else {
+                Some(AwsPollerError::NetworkError("down".to_owned()))
+            }
+        }
+
+        fn poll(&self) -> PollerResult<()> {
+            self.polls.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn counters(&self) -> Box<Collector> {
+            Box::new(CollectorGroup::new(Vec::new()))
+        }
+    }
+
+    fn fake_poller(healthy: bool) -> (FakePoller, Arc<AtomicBool>, Arc<AtomicUsize>) {
+        let healthy = Arc::new(AtomicBool::new(healthy));
+        let polls = Arc::new(AtomicUsize::new(0));
+        (FakePoller { healthy: healthy.clone(), polls: polls.clone() }, healthy, polls)
+    }
+
+    #[test]
+    fn failing_check_fails_startup_unless_degraded() {
+        let readiness = Readiness::new();
+        assert!(CheckedPoller::new(fake_poller(false).0, &readiness, false).is_err());
+        assert!(readiness.degraded_pollers().is_empty());
+        let _poller = CheckedPoller::new(fake_poller(false).0, &readiness, true).unwrap();
+        assert_eq!(readiness.degraded_pollers(), vec!["fake".to_owned()]);
+    }
+
+    #[test]
+    fn degraded_poller_starts_polling_once_check_passes() {
+        let (inner, healthy, polls) = fake_poller(false);
+        let readiness = Readiness::new();
+        let poller = CheckedPoller::new(inner, &readiness, true).unwrap();
+        assert_eq!(poller.poll(), Err(AwsPollerError::NetworkError("down".to_owned())));
+        assert_eq!(polls.load(Ordering::SeqCst), 0);
+        healthy.store(true, Ordering::SeqCst);
+        assert_eq!(poller.poll(), Ok(()));
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+        assert!(readiness.degraded_pollers().is_empty());
+    }
+
+    fn snapshot_collector(action: StaleSeriesAction) -> (SnapshotCollector, GaugeVec) {
+        let gauges = GaugeVec::new(Opts::new("fake_gauge", "Fake gauge"), &["id"]).unwrap();
+        let max_staleness = MaxStalenessSettings { failed_polls: Some(2), seconds: None, action: Some(action) };
+        let collector = SnapshotCollector::new("fake", Box::new(gauges.clone()), Some(max_staleness)).unwrap();
+        (collector, gauges)
+    }
+
+    fn family_names(families: &Vec<MetricFamily>) -> Vec<&str> {
+        families.iter().map(|f| f.get_name()).collect()
+    }
+
+    #[test]
+    fn snapshot_reports_data_age_before_first_poll() {
+        let (collector, _) = snapshot_collector(StaleSeriesAction::Drop);
+        let families = collector.collect();
+        assert_eq!(family_names(&families), vec!["deucalion_data_age_seconds"]);
+    }
+
+    #[test]
+    fn snapshot_is_kept_until_it_gets_stale() {
+        let (collector, gauges) = snapshot_collector(StaleSeriesAction::Drop);
+        gauges.with_label_values(&["i-1"]).set(1.0);
+        collector.publish(&gauges);
+        // later updates are not visible until published
+        gauges.with_label_values(&["i-2"]).set(1.0);
+        collector.fail();
+        let families = collector.collect();
+        assert_eq!(family_names(&families), vec!["fake_gauge", "deucalion_data_age_seconds"]);
+        assert_eq!(families[0].get_metric().len(), 1);
+        collector.fail();
+        assert_eq!(family_names(&collector.collect()), vec!["deucalion_data_age_seconds"]);
+    }
+
+    #[test]
+    fn stale_series_are_marked() {
+        let (collector, gauges) = snapshot_collector(StaleSeriesAction::Mark);
+        gauges.with_label_values(&["i-1"]).set(1.0);
+        collector.publish(&gauges);
+        collector.fail();
+        collector.fail();
+        let families = collector.collect();
+        let labels = families[0].get_metric()[0].get_label();
+        assert!(labels.iter().any(|l| l.get_name() == "stale" && l.get_value() == "true"));
+    }
+}
@@ -0,0 +1,151 @@
+use serde::Serialize;
+use serde_json;
+use prometheus::Collector;
+use config::{DeucalionSettings, ScrapeSettingsProvider, AwsInstancesPollerSettingsProvider,
+             AwsSpotPricesPollerSettingsProvider, AwsSpotRequestsPollerSettingsProvider,
+             AwsAccountLimitsPollerSettingsProvider, AwsInstanceStatusPollerSettingsProvider,
+             AwsAutoScalingPollerSettingsProvider, AwsCloudWatchPollerSettingsProvider,
+             AwsElbPollerSettingsProvider, AwsRdsPollerSettingsProvider};
+use poller::{Poller, CheckedPoller, Readiness};
+use aws_poller::{AwsInstancesPoller, AwsSpotPricesPoller, AwsSpotRequestsPoller, AwsAccountLimitsPoller,
+                 AwsInstanceStatusPoller, SpotPriceTable, InstanceInventory, PollerResult};
+use aws_autoscaling_poller::AwsAutoScalingPoller;
+use aws_cloudwatch_poller::{AwsCloudWatchPoller, AwsInstanceCloudWatchPoller};
+use aws_elb_poller::AwsElbPoller;
+use aws_rds_poller::AwsRdsPoller;
+use aws_connector::HttpClientFactory;
+use instance_types::InstanceTypeCatalogue;
+
+/// Names of AWS pollers built from the configuration.
+pub const POLLERS: &'static [&'static str] = &["instances", "instance_cloudwatch", "spot_prices", "spot_requests",
+                                               "account_limits", "instance_status", "autoscaling", "cloudwatch",
+                                               "elb", "rds"];
+
+/// Builds AWS pollers by name. State shared between pollers outlives pollers rebuilt on
+/// configuration reload.
+pub struct PollerBuilder {
+    http_clients: HttpClientFactory,
+    instance_types: InstanceTypeCatalogue,
+    spot_prices: SpotPriceTable,
+    inventory: InstanceInventory,
+    readiness: Readiness,
+    degraded_startup: bool
+}
+
+impl PollerBuilder {
+    pub fn new(http_clients: HttpClientFactory, instance_types: InstanceTypeCatalogue, readiness: Readiness,
+               degraded_startup: bool) -> PollerBuilder {
+        PollerBuilder {
+            http_clients: http_clients,
+            instance_types: instance_types,
+            spot_prices: SpotPriceTable::new(),
+            inventory: InstanceInventory::new(),
+            readiness: readiness,
+            degraded_startup: degraded_startup
+        }
+    }
+
+    /// Returns `None` if the poller is not configured.
+    pub fn build(&self, name: &str, config: &DeucalionSettings) -> PollerResult<Option<Box<Poller>>> {
+        let http_clients = &self.http_clients;
+        match name {
+            "instances" => self.checked(AwsInstancesPoller::new(
+                config, http_clients, self.instance_types.clone(), self.spot_prices.clone(),
+                self.inventory.clone()).map(Some)),
+            "instance_cloudwatch" => self.checked(AwsInstanceCloudWatchPoller::new(
+                config, http_clients, self.inventory.clone())),
+            "spot_prices" => self.checked(AwsSpotPricesPoller::new(
+                config, http_clients, self.instance_types.clone(), self.spot_prices.clone()).map(Some)),
+            "spot_requests" => self.checked(AwsSpotRequestsPoller::new(config, http_clients)),
+            "account_limits" => self.checked(AwsAccountLimitsPoller::new(
+                config, http_clients, self.instance_types.clone())),
+            "instance_status" => self.checked(AwsInstanceStatusPoller::new(config, http_clients)),
+            "autoscaling" => self.checked(AwsAutoScalingPoller::new(config, http_clients)),
+            "cloudwatch" => self.checked(AwsCloudWatchPoller::new(config, http_clients)),
+            "elb" => self.checked(AwsElbPoller::new(config, http_clients)),
+            "rds" => self.checked(AwsRdsPoller::new(config, http_clients)),
+            _ => Ok(None)
+        }
+    }
+
+    fn checked<P: Poller + 'static>(&self, poller: PollerResult<Option<P>>) -> PollerResult<Option<Box<Poller>>> {
+        Ok(CheckedPoller::new_optional(poller, &self.readiness, self.degraded_startup)?
+            .map(|p| Box::new(p) as Box<Poller>))
+    }
+
+    pub fn counters(&self) -> Box<Collector> {
+        self.http_clients.counters()
+    }
+}
+
+/// Fingerprints of settings of every poller, a poller is rebuilt when its fingerprint changes.
+pub fn fingerprints(config: &DeucalionSettings) -> Vec<(String, String)> {
+    POLLERS.iter()
+        .map(|&name| {
+            let settings = match name {
+                "instances" | "instance_cloudwatch" => fingerprint(&config.aws_instances_poller_settings()),
+                "spot_prices" => fingerprint(&config.aws_spot_prices_poller_settings()),
+                "spot_requests" => fingerprint(&config.aws_spot_requests_poller_settings()),
+                "account_limits" => fingerprint(&config.aws_account_limits_poller_settings()),
+                "instance_status" => fingerprint(&config.aws_instance_status_poller_settings()),
+                "autoscaling" => fingerprint(&config.aws_autoscaling_poller_settings()),
+                "cloudwatch" => fingerprint(&config.aws_cloudwatch_poller_settings()),
+                "elb" => fingerprint(&config.aws_elb_poller_settings()),
+                "rds" => fingerprint(&config.aws_rds_poller_settings()),
+                _ => String::new()
+            };
+            (name.to_owned(), format!("{} {}", settings, fingerprint(&config.poller_schedule(name))))
+        })
+        .collect()
+}
+
+/// Fingerprint of settings applied on restart only, a reload changing them is rejected.
+pub fn restart_fingerprint(config: &DeucalionSettings) -> String {
+    fingerprint(&config.restart_only_settings())
+}
+
+/// JSON objects are serialized with sorted keys, so that maps in settings compare equal.
+fn fingerprint<T: Serialize>(settings: &T) -> String {
+    serde_json::to_value(settings).map(|v| v.to_string()).unwrap_or(String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    fn config(extra: &str) -> DeucalionSettings {
+        serde_yaml::from_str(&format!("
+aws_instances_poller_settings: {{ region: us-east-1, expose_tags: [Name] }}
+aws_spot_prices_poller_settings: {{ region: us-east-1 }}
+scrape_settings:
+    listen_on: 0.0.0.0:8082
+{}", extra)).unwrap()
+    }
+
+    fn fingerprint_of<'a>(fingerprints: &'a Vec<(String, String)>, name: &str) -> &'a str {
+        &fingerprints.iter().find(|f| f.0 == name).unwrap().1
+    }
+
+    #[test]
+    fn fingerprints_change_with_poller_settings_and_schedule_only() {
+        let base = fingerprints(&config(""));
+        assert_eq!(base.len(), POLLERS.len());
+        assert_eq!(base, fingerprints(&config("    polling_period: 30")));
+        let scheduled = fingerprints(&config("    pollers: { rds: { mode: on_scrape } }"));
+        assert!(fingerprint_of(&base, "rds") != fingerprint_of(&scheduled, "rds"));
+        assert_eq!(fingerprint_of(&base, "elb"), fingerprint_of(&scheduled, "elb"));
+        let changed = fingerprints(&config("aws_rds_poller_settings: { region: eu-west-1, expose_tags: [] }"));
+        assert!(fingerprint_of(&base, "rds") != fingerprint_of(&changed, "rds"));
+        assert_eq!(fingerprint_of(&base, "instances"), fingerprint_of(&changed, "instances"));
+    }
+
+    #[test]
+    fn restart_fingerprint_ignores_poller_schedules() {
+        let base = restart_fingerprint(&config(""));
+        assert_eq!(base, restart_fingerprint(&config("    pollers: { rds: { mode: on_scrape } }")));
+        assert!(base != restart_fingerprint(&config("    polling_period: 30")));
+        assert!(base != restart_fingerprint(&config("http_client_settings: { requests_per_second: 5 }")));
+        assert!(base != restart_fingerprint(&config("instance_types_settings: { refresh_period: 60 }")));
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use prometheus::{Opts, CounterVec, Collector};
+use prometheus::Error as PrometheusError;
+use deadline;
+
+/// Token bucket which lets callers wait for their turn instead of failing, so that requests
+/// are spread evenly. Burst size equals to one second worth of requests.
+pub struct TokenBucket {
+    requests_per_second: f64,
+    state: Mutex<(f64, Instant)>
+}
+
+impl TokenBucket {
+    fn new(requests_per_second: f64) -> TokenBucket {
+        TokenBucket {
+            requests_per_second: requests_per_second,
+            state: Mutex::new((requests_per_second.max(1.0), Instant::now()))
+        }
+    }
+
+    /// Takes a token and returns the time spent waiting for it.
+    pub fn acquire(&self) -> Duration {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (ref mut tokens, ref mut updated) = *state;
+            let now = Instant::now();
+            let elapsed = now.duration_since(*updated);
+            let refill = (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9) * self.requests_per_second;
+            *tokens = (*tokens + refill).min(self.requests_per_second.max(1.0));
+            *updated = now;
+            // the token is reserved right away, so that concurrent callers queue up
+            *tokens -= 1.0;
+            if *tokens >= 0.0 {
+                return Duration::from_secs(0);
+            }
+            let secs = -*tokens / self.requests_per_second;
+            Duration::new(secs as u64, (secs.fract() * 1e9) as u32)
+        };
+        // there is no point in waiting past the deadline, the request will fail anyway
+        let wait = match deadline::remaining() {
+            Some(r) if r < wait => r,
+            _ => wait
+        };
+        thread::sleep(wait);
+        wait
+    }
+}
+
+/// Rate limiters shared by all pollers using the same credentials in the same region, as AWS
+/// throttles API calls per account and region.
+#[derive(Clone)]
+pub struct RateLimiters {
+    requests_per_second: Option<f64>,
+    buckets: Arc<Mutex<HashMap<(String, String), Arc<TokenBucket>>>>,
+    wait_seconds: CounterVec
+}
+
+impl RateLimiters {
+    pub fn new(requests_per_second: Option<f64>) -> Result<RateLimiters, PrometheusError> {
+        Ok(RateLimiters {
+            requests_per_second: requests_per_second,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            wait_seconds: CounterVec::new(
+                Opts::new("deucalion_rate_limiter_wait_seconds_total",
+                          "Time spent waiting for the AWS API rate limiter"),
+                &["credentials", "region"])?
+        })
+    }
+
+    /// Returns `None` if rate limiting is disabled. Buckets are identified by `credentials_key`,
+    /// while the wait time is exported with `credentials_label`, which is safe to expose.
+    pub fn get(&self, credentials_key: &str, credentials_label: &str, region: &str) -> Option<RateLimiter> {
+        self.requests_per_second.map(|rps| {
+            let bucket = self.buckets.lock().unwrap()
+                .entry((credentials_key.to_owned(), region.to_owned()))
+                .or_insert_with(|| Arc::new(TokenBucket::new(rps)))
+                .clone();
+            RateLimiter {
+                bucket: bucket,
+                credentials_label: credentials_label.to_owned(),
+                region: region.to_owned(),
+                wait_seconds: self.wait_seconds.clone()
+            }
+        })
+    }
+
+    pub fn counters(&self) -> Box<Collector> {
+        Box::new(self.wait_seconds.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<TokenBucket>,
+    credentials_label: String,
+    region: String,
+    wait_seconds: CounterVec
+}
+
+impl RateLimiter {
+    pub fn acquire(&self) {
+        let wait = self.bucket.acquire();
+        if let Ok(c) = self.wait_seconds.get_metric_with_label_values(&[self.credentials_label.as_str(), self.region.as_str()]) {
+            let _ = c.inc_by(wait.as_secs() as f64 + wait.subsec_nanos() as f64 * 1e-9);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_and_then_spreads_requests() {
+        let bucket = TokenBucket::new(10.0);
+        for _ in 0..10 {
+            assert_eq!(bucket.acquire(), Duration::from_secs(0));
+        }
+        let wait = bucket.acquire();
+        assert!(wait > Duration::from_millis(50) && wait <= Duration::from_millis(100), "waited {:?}", wait);
+    }
+
+    #[test]
+    fn buckets_are_shared_per_credentials_and_region() {
+        let limiters = RateLimiters::new(Some(5.0)).unwrap();
+        let bucket = |credentials: &str, region: &str| limiters.get(credentials, "label", region).unwrap().bucket;
+        assert!(Arc::ptr_eq(&bucket("default", "us-east-1"), &bucket("default", "us-east-1")));
+        assert!(!Arc::ptr_eq(&bucket("default", "us-east-1"), &bucket("default", "eu-west-1")));
+        assert!(!Arc::ptr_eq(&bucket("default", "us-east-1"), &bucket("profile:prod:", "us-east-1")));
+        assert!(RateLimiters::new(None).unwrap().get("default", "default", "us-east-1").is_none());
+    }
+}
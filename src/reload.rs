@@ -0,0 +1,145 @@
+use std::fs;
+use std::io::{stderr, Write};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use libc;
+use prometheus::{Opts, Gauge, Collector};
+use prometheus::Error as PrometheusError;
+use config::{DeucalionSettings, ScrapeSettingsProvider};
+use poller::{CollectorGroup, Readiness};
+use periodic::Scheduler;
+use pollers::{self, PollerBuilder};
+
+/// How often the configuration file is checked for changes.
+const WATCH_INTERVAL_SECS: u64 = 5;
+
+static RELOAD_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn request_reload(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Reloads the configuration on SIGHUP or when the file changes. Pollers with changed settings
+/// are rebuilt, the others keep running. The new configuration is rejected as a whole if it can not
+/// be parsed or any of the changed pollers can not be built. Scrape, HTTP client and instance types
+/// settings are applied on restart only, so configuration changing them is rejected as well.
+pub struct ConfigReloader {
+    filename: String,
+    builder: PollerBuilder,
+    scheduler: Scheduler,
+    readiness: Readiness,
+    fingerprints: Vec<(String, String)>,
+    restart_fingerprint: String,
+    modified: Option<SystemTime>,
+    successful: Gauge,
+    success_time: Gauge
+}
+
+impl ConfigReloader {
+    pub fn new(filename: &str, config: &DeucalionSettings, builder: PollerBuilder, scheduler: Scheduler,
+               readiness: Readiness) -> Result<ConfigReloader, PrometheusError> {
+        let result = ConfigReloader {
+            filename: filename.to_owned(),
+            builder: builder,
+            scheduler: scheduler,
+            readiness: readiness,
+            fingerprints: pollers::fingerprints(config),
+            restart_fingerprint: pollers::restart_fingerprint(config),
+            modified: Self::modified(filename),
+            successful: Gauge::with_opts(Opts::new("deucalion_config_last_reload_successful",
+                                                   "Whether the last configuration reload attempt was successful"))?,
+            success_time: Gauge::with_opts(Opts::new("deucalion_config_last_reload_success_timestamp_seconds",
+                                                     "Timestamp of the last successful configuration reload"))?
+        };
+        result.successful.set(1.0);
+        result.success_time.set(now_seconds());
+        Ok(result)
+    }
+
+    pub fn counters(&self) -> Box<Collector> {
+        Box::new(CollectorGroup::new(vec![
+            Box::new(self.successful.clone()),
+            Box::new(self.success_time.clone()),
+            self.builder.counters(),
+        ]))
+    }
+
+    /// Watches for reload requests in the background.
+    pub fn watch(mut self) {
+        unsafe {
+            libc::signal(libc::SIGHUP, request_reload as libc::sighandler_t);
+        }
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(WATCH_INTERVAL_SECS));
+            if self.readiness.is_shutting_down() {
+                return;
+            }
+            let modified = Self::modified(&self.filename);
+            if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) || modified != self.modified {
+                match self.reload(modified) {
+                    Ok(()) => {
+                        println!("Configuration reloaded");
+                        self.successful.set(1.0);
+                        self.success_time.set(now_seconds());
+                    }
+                    Err(e) => {
+                        let _ = writeln!(&mut stderr(), "Configuration rejected, keeping the previous one: {}", e);
+                        self.successful.set(0.0);
+                    }
+                }
+            }
+        });
+    }
+
+    /// The modification time is recorded once the file is parsed, so that a file caught in the
+    /// middle of a write is read again.
+    fn reload(&mut self, modified: Option<SystemTime>) -> Result<(), String> {
+        let config = DeucalionSettings::from_filename(&self.filename)
+            .map_err(|e| format!("{:?}", e))?;
+        self.modified = modified;
+        if pollers::restart_fingerprint(&config) != self.restart_fingerprint {
+            return Err("scrape, HTTP client or instance types settings changed, restart required".to_owned());
+        }
+        let fingerprints = pollers::fingerprints(&config);
+        let changed: Vec<String> = fingerprints.iter()
+            .filter(|f| !self.fingerprints.contains(f))
+            .map(|f| f.0.clone())
+            .collect();
+        // build all changed pollers before replacing any of them
+        let saved_readiness = self.readiness.save();
+        let mut rebuilt = Vec::with_capacity(changed.len());
+        for name in changed {
+            match self.builder.build(&name, &config) {
+                Ok(p) => rebuilt.push((name, p)),
+                Err(e) => {
+                    self.readiness.restore(saved_readiness);
+                    return Err(format!("could not initialize {} poller: {:?}", name, e));
+                }
+            }
+        }
+        for (name, poller) in rebuilt {
+            match poller {
+                Some(p) => {
+                    println!("Rebuilding {} poller", name);
+                    self.scheduler.schedule(p, config.poller_schedule(&name));
+                }
+                None => {
+                    println!("Removing {} poller", name);
+                    self.scheduler.remove(&name);
+                    self.readiness.remove(&name);
+                }
+            }
+        }
+        self.fingerprints = fingerprints;
+        Ok(())
+    }
+
+    fn modified(filename: &str) -> Option<SystemTime> {
+        fs::metadata(filename).and_then(|m| m.modified()).ok()
+    }
+}
+
+fn now_seconds() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as f64).unwrap_or(0.0)
+}
@@ -1,25 +1,79 @@
-use hyper::header::ContentType;
+use hyper::header::{ContentType, Authorization, Bearer};
+use hyper::method::Method;
 use hyper::server::{Request, Response, Handler};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
 use hyper::mime::Mime;
 use prometheus::Encoder;
 use prometheus::{Registry};
+use url::form_urlencoded;
+use poller::Readiness;
+use periodic::Scheduler;
 
 pub struct DeucalionHandler<E: Encoder + 'static> {
     encoder: E,
-    registry: Registry
+    registry: Registry,
+    readiness: Readiness,
+    scheduler: Scheduler,
+    refresh_token: Option<String>
 }
 
 impl<E: Encoder + 'static> DeucalionHandler<E> {
-    pub fn new(encoder: E, registry: Registry) -> DeucalionHandler<E> {
+    pub fn new(encoder: E, registry: Registry, readiness: Readiness, scheduler: Scheduler,
+               refresh_token: Option<String>) -> DeucalionHandler<E> {
         DeucalionHandler{
             encoder:encoder,
-            registry: registry
+            registry: registry,
+            readiness: readiness,
+            scheduler: scheduler,
+            refresh_token: refresh_token
         }
     }
-}
 
-impl<E: Encoder + 'static + Send + Sync> Handler for DeucalionHandler<E> {
-    fn handle(&self, _: Request, mut res: Response) {
+    /// Wakes up the poller named in the query, the poll runs asynchronously.
+    fn handle_refresh(&self, req: &Request, mut res: Response, query: &str) {
+        let (status, body) = match self.refresh_token {
+            None => (StatusCode::NotFound, "refresh is disabled".to_owned()),
+            Some(_) if req.method != Method::Post => (StatusCode::MethodNotAllowed, "use POST".to_owned()),
+            Some(ref token) if !req.headers.get::<Authorization<Bearer>>()
+                .map_or(false, |a| tokens_match(a.token.as_bytes(), token.as_bytes())) =>
+                (StatusCode::Unauthorized, "invalid token".to_owned()),
+            Some(_) => match form_urlencoded::parse(query.as_bytes()).find(|&(ref k, _)| k == "poller") {
+                None => (StatusCode::BadRequest, "poller is not specified".to_owned()),
+                Some((_, name)) => if self.scheduler.poll_now(&name) {
+                    (StatusCode::Accepted, format!("refreshing {}", name))
+                } else {
+                    (StatusCode::NotFound, format!("unknown poller {}", name))
+                }
+            }
+        };
+        *res.status_mut() = status;
+        res.send(format!("{}\n", body).as_bytes()).unwrap();
+    }
+
+    /// Responds with 503 and the list of degraded pollers until all of them are ready.
+    fn handle_ready(&self, mut res: Response) {
+        let degraded = self.readiness.degraded_pollers();
+        if self.readiness.is_shutting_down() {
+            *res.status_mut() = StatusCode::ServiceUnavailable;
+            res.send(b"shutting down\n").unwrap();
+        } else if degraded.is_empty() {
+            res.send(b"ready\n").unwrap();
+        } else {
+            *res.status_mut() = StatusCode::ServiceUnavailable;
+            res.send(format!("degraded: {}\n", degraded.join(", ")).as_bytes()).unwrap();
+        }
+    }
+
+    /// Pollers in `on_scrape` mode are polled before metrics are gathered. Responds with 503
+    /// once the server is stopped.
+    fn handle_metrics(&self, mut res: Response) {
+        if self.readiness.is_stopped() {
+            *res.status_mut() = StatusCode::ServiceUnavailable;
+            res.send(b"shutting down\n").unwrap();
+            return;
+        }
+        self.scheduler.poll_on_scrape();
         let metric_families = self.registry.gather();
         let mut buffer = vec![];
         self.encoder.encode(&metric_families, &mut buffer).unwrap();
@@ -29,4 +83,32 @@ impl<E: Encoder + 'static + Send + Sync> Handler for DeucalionHandler<E> {
     }
 }
 
+impl<E: Encoder + 'static + Send + Sync> Handler for DeucalionHandler<E> {
+    fn handle(&self, req: Request, res: Response) {
+        match req.uri {
+            RequestUri::AbsolutePath(ref path) if path == "/-/ready" => self.handle_ready(res),
+            RequestUri::AbsolutePath(ref path) if path.split('?').next() == Some("/-/refresh") =>
+                self.handle_refresh(&req, res, path.splitn(2, '?').nth(1).unwrap_or("")),
+            _ => self.handle_metrics(res)
+        }
+    }
+}
+
+/// Compares tokens in constant time not to reveal the matching prefix.
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn matches_equal_tokens_only() {
+        assert!(tokens_match(b"secret", b"secret"));
+        assert!(!tokens_match(b"secret", b"secreT"));
+        assert!(!tokens_match(b"secret", b"secret2"));
+        assert!(!tokens_match(b"", b"secret"));
+        assert!(tokens_match(b"", b""));
+    }
+}
@@ -1,4 +1,8 @@
 use std::sync::{Arc, Mutex, Condvar};
+use std::time::Duration;
+use std::thread;
+use std::process;
+use std::io::{stderr, Write};
 use ctrlc;
 
 pub struct TerminationGuard {
@@ -34,3 +38,12 @@ impl Drop for TerminationGuard  {
         }
     }
 }
+
+/// Exits the process with non-zero code unless it exits on its own within `timeout`.
+pub fn exit_after(timeout: Duration) {
+    thread::spawn(move || {
+        thread::sleep(timeout);
+        let _ = writeln!(&mut stderr(), "Shutdown took longer than {:?}, exiting", timeout);
+        process::exit(1);
+    });
+}